@@ -0,0 +1,83 @@
+//! Feed arbitrary packets to the driver through a fake sensor interface.
+//! Run with `cargo fuzz run handle_packets` from the repository root.
+#![no_main]
+
+use bno080::interface::SensorInterface;
+use bno080::wrapper::BNO080;
+use embedded_hal::delay::DelayNs;
+use libfuzzer_sys::fuzz_target;
+
+struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// Replays the fuzz input as a series of packets,
+/// each preceded by a length byte
+struct ReplayInterface<'a> {
+    data: &'a [u8],
+}
+
+impl SensorInterface for ReplayInterface<'_> {
+    type SensorError = ();
+
+    fn setup(
+        &mut self,
+        _delay_source: &mut impl DelayNs,
+    ) -> Result<(), Self::SensorError> {
+        Ok(())
+    }
+
+    fn write_packet(
+        &mut self,
+        _packet: &[u8],
+    ) -> Result<(), Self::SensorError> {
+        Ok(())
+    }
+
+    fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        let Some((&len, rest)) = self.data.split_first() else {
+            return Ok(0);
+        };
+        let len = (len as usize).min(rest.len()).min(recv_buf.len());
+        recv_buf[..len].copy_from_slice(&rest[..len]);
+        self.data = &rest[len..];
+        Ok(len)
+    }
+
+    fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        _delay_source: &mut impl DelayNs,
+        _max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        self.read_packet(recv_buf)
+    }
+
+    fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        self.write_packet(send_buf)?;
+        self.read_packet(recv_buf)
+    }
+
+    fn requires_soft_reset(&self) -> bool {
+        true
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut shub = BNO080::new_with_interface(ReplayInterface { data });
+    let mut delay = NoDelay;
+    let _ = shub.init(&mut delay);
+    // every packet consumes at least one byte of input
+    for _ in 0..data.len() {
+        let _ = shub.handle_one_message(&mut delay, 1);
+    }
+});
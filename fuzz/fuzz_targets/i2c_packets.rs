@@ -0,0 +1,52 @@
+//! Feed arbitrary bus data to the driver through the I2C interface.
+//! Run with `cargo fuzz run i2c_packets` from the repository root.
+#![no_main]
+
+use bno080::interface::I2cInterface;
+use bno080::wrapper::BNO080;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, Operation};
+use libfuzzer_sys::fuzz_target;
+
+struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// An I2C port whose reads return the fuzz input, then zeros
+struct ReplayPort<'a> {
+    data: &'a [u8],
+}
+
+impl ErrorType for ReplayPort<'_> {
+    type Error = ErrorKind;
+}
+
+impl I2c for ReplayPort<'_> {
+    fn transaction(
+        &mut self,
+        _address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        for op in operations {
+            if let Operation::Read(buf) = op {
+                let len = buf.len().min(self.data.len());
+                buf[..len].copy_from_slice(&self.data[..len]);
+                buf[len..].fill(0);
+                self.data = &self.data[len..];
+            }
+        }
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let port = ReplayPort { data };
+    let mut shub = BNO080::new_with_interface(I2cInterface::default(port));
+    let mut delay = NoDelay;
+    let _ = shub.init(&mut delay);
+    for _ in 0..data.len() {
+        let _ = shub.handle_one_message(&mut delay, 1);
+    }
+});
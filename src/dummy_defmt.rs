@@ -1,6 +1,3 @@
-/// empty
-pub trait Format {}
-
 /// no-op
 macro_rules! println {
     ($($tt:tt)*) => {};
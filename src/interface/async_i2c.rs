@@ -0,0 +1,272 @@
+use super::i2c::{ALTERNATE_ADDRESS, DEFAULT_ADDRESS, SEG_RECV_BUF_LEN};
+use super::{AsyncSensorInterface, NoPin, SensorCommon, PACKET_HEADER_LENGTH};
+use crate::Error;
+use embassy_futures::select::{select, Either};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+
+#[cfg(feature = "defmt-03")]
+use defmt_03 as defmt;
+
+#[cfg(feature = "defmt-03")]
+use defmt::println;
+
+/// Async version of `I2cInterface`, which awaits the i2c port
+/// rather than blocking on it.
+/// - HINTN: optional hardware interrupt line, which the sensor hub pulls low
+///   when it has data available. With it, reads await the sensor hub
+///   signalling; without it, the bus is polled once per millisecond.
+pub struct AsyncI2cInterface<I2C, IN = NoPin> {
+    /// i2c port
+    i2c_port: I2C,
+    /// address for i2c communications with the sensor hub
+    address: u8,
+    /// buffer for receiving segments of packets from the sensor hub
+    seg_recv_buf: [u8; SEG_RECV_BUF_LEN],
+    /// line the sensor hub uses to signal that data is available
+    hintn: Option<IN>,
+}
+
+impl<I2C, CommE> AsyncI2cInterface<I2C>
+where
+    I2C: I2c<Error = CommE>,
+{
+    pub fn default(i2c: I2C) -> Self {
+        Self::new(i2c, DEFAULT_ADDRESS)
+    }
+
+    pub fn alternate(i2c: I2C) -> Self {
+        Self::new(i2c, ALTERNATE_ADDRESS)
+    }
+
+    pub fn new(i2c: I2C, addr: u8) -> Self {
+        Self {
+            i2c_port: i2c,
+            address: addr,
+            seg_recv_buf: [0; SEG_RECV_BUF_LEN],
+            hintn: None,
+        }
+    }
+}
+
+impl<I2C, IN> AsyncI2cInterface<I2C, IN> {
+    /// Await the sensor hub signalling on its HINTN line
+    /// that data is available, rather than polling the bus
+    pub fn with_hintn<P: Wait>(self, hintn: P) -> AsyncI2cInterface<I2C, P> {
+        AsyncI2cInterface {
+            i2c_port: self.i2c_port,
+            address: self.address,
+            seg_recv_buf: self.seg_recv_buf,
+            hintn: Some(hintn),
+        }
+    }
+
+    pub fn free(self) -> I2C {
+        self.i2c_port
+    }
+}
+
+impl<I2C, IN, CommE> AsyncI2cInterface<I2C, IN>
+where
+    I2C: I2c<Error = CommE>,
+    IN: Wait,
+{
+    /// Wait up to `max_ms` for the sensor hub to signal on HINTN
+    /// that it has data available.
+    /// Return true if it did, false if `max_ms` elapsed first
+    async fn wait_for_hintn(
+        hintn: &mut IN,
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<bool, Error<CommE, IN::Error>> {
+        let hintn_low = hintn.wait_for_low();
+        let timeout = delay_source.delay_ms(max_ms as u32);
+        match select(hintn_low, timeout).await {
+            Either::First(rc) => rc.map(|_| true).map_err(Error::Pin),
+            Either::Second(_) => Ok(false),
+        }
+    }
+
+    /// Read just the header of the next packet into the segment buffer
+    async fn read_packet_header(
+        &mut self,
+    ) -> Result<(), Error<CommE, IN::Error>> {
+        self.seg_recv_buf[..PACKET_HEADER_LENGTH].fill(0);
+        self.i2c_port
+            .read(self.address, &mut self.seg_recv_buf[..PACKET_HEADER_LENGTH])
+            .await
+            .map_err(Error::Comm)
+    }
+
+    /// Read the rest of the packet whose header was just read, if any,
+    /// as with `I2cInterface`
+    async fn read_announced_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Error<CommE, IN::Error>> {
+        let header = &self.seg_recv_buf[..PACKET_HEADER_LENGTH];
+        let packet_len = SensorCommon::parse_packet_header(header);
+        if packet_len <= PACKET_HEADER_LENGTH {
+            return Ok(packet_len);
+        }
+        if SensorCommon::is_continuation(header) {
+            // the tail of a packet we failed to read earlier
+            #[cfg(feature = "defmt-03")]
+            println!("orphan segment {}", packet_len);
+            self.read_sized_packet(packet_len, &mut []).await?;
+            return Err(Error::OrphanSegment);
+        }
+        self.read_sized_packet(packet_len, recv_buf).await
+    }
+
+    /// Read the whole packet whose header was just read,
+    /// returning its length even when it did not fit in `packet_recv_buf`
+    async fn read_sized_packet(
+        &mut self,
+        total_packet_len: usize,
+        packet_recv_buf: &mut [u8],
+    ) -> Result<usize, Error<CommE, IN::Error>> {
+        let mut packet_header = [0u8; PACKET_HEADER_LENGTH];
+        packet_header
+            .copy_from_slice(&self.seg_recv_buf[..PACKET_HEADER_LENGTH]);
+        let mut segment_seq = packet_header[3];
+
+        if total_packet_len < SEG_RECV_BUF_LEN
+            && total_packet_len <= packet_recv_buf.len()
+        {
+            //read directly into the provided receive buffer
+            self.i2c_port
+                .read(self.address, &mut packet_recv_buf[..total_packet_len])
+                .await
+                .map_err(Error::Comm)?;
+            SensorCommon::check_restart_header(
+                &packet_header,
+                &packet_recv_buf[..PACKET_HEADER_LENGTH],
+            )?;
+            return Ok(total_packet_len);
+        }
+
+        let mut remaining_body_len = total_packet_len - PACKET_HEADER_LENGTH;
+        let mut already_read_len = PACKET_HEADER_LENGTH;
+        while remaining_body_len > 0 {
+            let segment_read_len = (remaining_body_len + PACKET_HEADER_LENGTH)
+                .min(SEG_RECV_BUF_LEN);
+
+            self.seg_recv_buf[..PACKET_HEADER_LENGTH].fill(0);
+            self.i2c_port
+                .read(self.address, &mut self.seg_recv_buf[..segment_read_len])
+                .await
+                .map_err(Error::Comm)?;
+            let segment_header = &self.seg_recv_buf[..PACKET_HEADER_LENGTH];
+            if already_read_len == PACKET_HEADER_LENGTH {
+                SensorCommon::check_restart_header(
+                    &packet_header,
+                    segment_header,
+                )?;
+            } else {
+                segment_seq = SensorCommon::check_segment_header(
+                    &packet_header,
+                    segment_seq,
+                    segment_header,
+                )?;
+            }
+
+            // only the body of each segment is transcribed:
+            // a packet too large for the receive buffer is drained,
+            // keeping only its leading part
+            let body_read_len = segment_read_len - PACKET_HEADER_LENGTH;
+            let kept_len = body_read_len
+                .min(packet_recv_buf.len().saturating_sub(already_read_len));
+            if kept_len > 0 {
+                packet_recv_buf[already_read_len..already_read_len + kept_len]
+                    .copy_from_slice(
+                        &self.seg_recv_buf[PACKET_HEADER_LENGTH
+                            ..PACKET_HEADER_LENGTH + kept_len],
+                    );
+            }
+            already_read_len += body_read_len;
+            remaining_body_len -= body_read_len;
+        }
+
+        let header_len = packet_recv_buf.len().min(PACKET_HEADER_LENGTH);
+        packet_recv_buf[..header_len]
+            .copy_from_slice(&packet_header[..header_len]);
+        Ok(total_packet_len)
+    }
+}
+
+impl<I2C, IN, CommE> AsyncSensorInterface for AsyncI2cInterface<I2C, IN>
+where
+    I2C: I2c<Error = CommE>,
+    IN: Wait,
+{
+    type SensorError = Error<CommE, IN::Error>;
+
+    fn requires_soft_reset(&self) -> bool {
+        true
+    }
+
+    async fn setup(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), Self::SensorError> {
+        delay_source.delay_ms(5).await;
+        Ok(())
+    }
+
+    async fn write_packet(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Self::SensorError> {
+        self.i2c_port
+            .write(self.address, packet)
+            .await
+            .map_err(Error::Comm)
+    }
+
+    /// Read one packet into the receive buffer
+    async fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        self.read_packet_header().await?;
+        self.read_announced_packet(recv_buf).await
+    }
+
+    async fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        if let Some(hintn) = self.hintn.as_mut() {
+            if !Self::wait_for_hintn(hintn, delay_source, max_ms).await? {
+                return Ok(0);
+            }
+            return self.read_packet(recv_buf).await;
+        }
+        // without HINTN, poll the sensor hub once per millisecond
+        for _ in 0..max_ms {
+            let read_size = self.read_packet(recv_buf).await?;
+            if read_size > 0 {
+                return Ok(read_size);
+            }
+            delay_source.delay_ms(1).await;
+        }
+        Ok(0)
+    }
+
+    async fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+        _delay_source: &mut impl DelayNs,
+    ) -> Result<usize, Self::SensorError> {
+        // Cannot use write_read with bno080,
+        // because it does not support repeated start with i2c.
+        self.write_packet(send_buf).await?;
+        recv_buf.fill(0);
+        self.read_packet(recv_buf).await
+    }
+}
@@ -0,0 +1,435 @@
+use super::spi::{SpiControlLines, WAKE_TIMEOUT_US};
+use super::{
+    AsyncSensorInterface, NoDelay, NoPin, SensorCommon, PACKET_HEADER_LENGTH,
+};
+#[cfg(feature = "defmt-03")]
+use crate::defmt;
+use crate::Error;
+use crate::Error::SensorUnresponsive;
+use crate::LogFormat;
+use embassy_futures::select::{select, Either};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+#[cfg(feature = "defmt-03")]
+use crate::defmt::println;
+
+/// Size of the scratch buffer used to skip the tail of oversized packets
+const DISCARD_CHUNK_LEN: usize = 32;
+/// Longest wait for the response to a packet sent, in milliseconds
+const RESPONSE_TIMEOUT_MS: u8 = 100;
+
+/// Async version of `SpiInterface`: rather than polling HINTN,
+/// it awaits the sensor hub signalling that it has data available.
+/// As with `SpiInterface`, a PS0/WAKE line can be added with `with_wake`.
+pub struct AsyncSpiInterface<SPI, CSN, IN, RSTN, WAK = NoPin, D = NoDelay> {
+    spi: SPI,
+    csn: CSN,
+    hintn: IN,
+    reset: RSTN,
+    wake: Option<WAK>,
+    /// delay source used to time the sensor hub's answer to WAKE
+    wake_delay: D,
+}
+
+impl<SPI, CSN, IN, RSTN, CommE, PinE>
+    AsyncSpiInterface<SPI, CSN, IN, RSTN, NoPin<PinE>>
+where
+    SPI: SpiDevice<Error = CommE>,
+    CSN: OutputPin<Error = PinE>,
+    IN: Wait<Error = PinE>,
+    RSTN: OutputPin<Error = PinE>,
+{
+    pub fn new(lines: SpiControlLines<SPI, CSN, IN, RSTN>) -> Self {
+        Self {
+            spi: lines.spi,
+            csn: lines.csn,
+            hintn: lines.hintn,
+            reset: lines.reset,
+            wake: None,
+            wake_delay: NoDelay,
+        }
+    }
+}
+
+impl<SPI, CSN, IN, RSTN, WAK, D, CommE, PinE>
+    AsyncSpiInterface<SPI, CSN, IN, RSTN, WAK, D>
+where
+    SPI: SpiDevice<Error = CommE>,
+    CSN: OutputPin<Error = PinE>,
+    IN: Wait<Error = PinE>,
+    RSTN: OutputPin<Error = PinE>,
+    WAK: OutputPin<Error = PinE>,
+    D: DelayNs,
+{
+    /// Drive the sensor hub's PS0/WAKE line, as with
+    /// `SpiInterface::with_wake`
+    pub fn with_wake<P: OutputPin<Error = PinE>, DW: DelayNs>(
+        self,
+        wake: P,
+        delay: DW,
+    ) -> AsyncSpiInterface<SPI, CSN, IN, RSTN, P, DW> {
+        AsyncSpiInterface {
+            spi: self.spi,
+            csn: self.csn,
+            hintn: self.hintn,
+            reset: self.reset,
+            wake: Some(wake),
+            wake_delay: delay,
+        }
+    }
+
+    /// Wait for HINTN to signal that the sensor needs attention.
+    /// Return true if it did, false if `max_ms` elapsed first
+    async fn wait_for_hintn(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<bool, Error<CommE, PinE>> {
+        let hintn_low = self.hintn.wait_for_low();
+        let timeout = delay_source.delay_ms(max_ms as u32);
+        match select(hintn_low, timeout).await {
+            Either::First(rc) => rc.map(|_| true).map_err(Error::Pin),
+            Either::Second(_) => Ok(false),
+        }
+    }
+
+    /// Pull WAK low, then write a packet once the sensor hub answers
+    /// by signalling on HINTN, waiting up to `WAKE_TIMEOUT_US`
+    async fn wake_and_write(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        if let Some(wake) = self.wake.as_mut() {
+            wake.set_low().map_err(Error::Pin)?;
+        }
+        let hintn_low = self.hintn.wait_for_low();
+        let timeout = self.wake_delay.delay_us(WAKE_TIMEOUT_US);
+        match select(hintn_low, timeout).await {
+            Either::First(rc) => rc.map_err(Error::Pin)?,
+            Either::Second(_) => {
+                #[cfg(feature = "defmt-03")]
+                println!("no answer to WAKE");
+                return Err(SensorUnresponsive);
+            }
+        }
+        self.write_selected(packet).await
+    }
+
+    /// Write a packet in a transaction of its own
+    async fn write_selected(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        self.csn.set_low().map_err(Error::Pin)?;
+        let rc = self.spi.write(packet).await.map_err(Error::Comm);
+        self.csn.set_high().map_err(Error::Pin)?;
+        rc
+    }
+
+    /// Read a complete packet while the sensor is selected,
+    /// returning its length even when it did not fit in `recv_buf`
+    async fn read_selected_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, CommE> {
+        // get just the header
+        self.spi
+            .transfer(&mut recv_buf[..PACKET_HEADER_LENGTH], &[])
+            .await?;
+        let packet_len = SensorCommon::parse_packet_header(
+            &recv_buf[..PACKET_HEADER_LENGTH],
+        );
+        if packet_len <= PACKET_HEADER_LENGTH {
+            return Ok(0);
+        }
+
+        // a packet too large for the receive buffer is drained,
+        // keeping only its leading part
+        let kept_len = packet_len.min(recv_buf.len());
+        //exchange 0xFF bytes for whatever the sensor is sending
+        recv_buf[PACKET_HEADER_LENGTH..kept_len].fill(0xFF);
+        self.spi
+            .transfer(&mut recv_buf[PACKET_HEADER_LENGTH..kept_len], &[])
+            .await?;
+
+        let mut discard_buf = [0u8; DISCARD_CHUNK_LEN];
+        let mut remaining_len = packet_len - kept_len;
+        while remaining_len > 0 {
+            let chunk_len = remaining_len.min(DISCARD_CHUNK_LEN);
+            self.spi
+                .transfer(&mut discard_buf[..chunk_len], &[])
+                .await?;
+            remaining_len -= chunk_len;
+        }
+
+        Ok(packet_len)
+    }
+}
+
+impl<SPI, CSN, IN, RS, WAK, D, CommE, PinE> AsyncSensorInterface
+    for AsyncSpiInterface<SPI, CSN, IN, RS, WAK, D>
+where
+    SPI: SpiDevice<Error = CommE>,
+    CSN: OutputPin<Error = PinE>,
+    IN: Wait<Error = PinE>,
+    RS: OutputPin<Error = PinE>,
+    WAK: OutputPin<Error = PinE>,
+    D: DelayNs,
+    CommE: core::fmt::Debug + LogFormat,
+    PinE: core::fmt::Debug + LogFormat,
+{
+    type SensorError = Error<CommE, PinE>;
+
+    fn requires_soft_reset(&self) -> bool {
+        false
+    }
+
+    async fn setup(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), Self::SensorError> {
+        // Deselect sensor
+        self.csn.set_high().map_err(Error::Pin)?;
+        // WAK/PS0 must be high when the sensor hub leaves reset,
+        // to select SPI mode: without a WAK pin, it must be tied high
+        if let Some(wake) = self.wake.as_mut() {
+            wake.set_high().map_err(Error::Pin)?;
+        }
+        self.reset.set_high().map_err(Error::Pin)?;
+
+        // reset cycle
+        self.reset.set_low().map_err(Error::Pin)?;
+        delay_source.delay_ms(2).await;
+        self.reset.set_high().map_err(Error::Pin)?;
+
+        // wait for sensor to set hintn pin after reset
+        if !self.wait_for_hintn(delay_source, 200).await? {
+            #[cfg(feature = "defmt-03")]
+            println!("sensor not ready");
+            return Err(SensorUnresponsive);
+        }
+
+        Ok(())
+    }
+
+    async fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+        delay_source: &mut impl DelayNs,
+    ) -> Result<usize, Self::SensorError> {
+        self.write_packet(send_buf).await?;
+
+        recv_buf[..PACKET_HEADER_LENGTH].fill(0);
+        if !self
+            .wait_for_hintn(delay_source, RESPONSE_TIMEOUT_MS)
+            .await?
+        {
+            //no packet to be read
+            return Ok(0);
+        }
+        self.read_packet(recv_buf).await
+    }
+
+    /// Write a packet, first waking the sensor hub if we have a WAK pin.
+    /// Fails with `SensorUnresponsive` if the sensor hub does not answer.
+    async fn write_packet(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Self::SensorError> {
+        if self.wake.is_none() {
+            return self.write_selected(packet).await;
+        }
+
+        let rc = self.wake_and_write(packet).await;
+        // release WAK whatever happened, reporting the first error
+        let released = match self.wake.as_mut() {
+            Some(wake) => wake.set_high().map_err(Error::Pin),
+            None => Ok(()),
+        };
+        rc.and(released)
+    }
+
+    /// Read a complete packet from the sensor
+    async fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        //Zero the header bytes are zeroed since we're not sending any data
+        recv_buf[..PACKET_HEADER_LENGTH].fill(0);
+
+        // grab this sensor
+        self.csn.set_low().map_err(Error::Pin)?;
+        let rc = self.read_selected_packet(recv_buf).await;
+        //release the sensor
+        self.csn.set_high().map_err(Error::Pin)?;
+
+        rc.map_err(Error::Comm)
+    }
+
+    async fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        if self.wait_for_hintn(delay_source, max_ms).await? {
+            return self.read_packet(recv_buf).await;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::AsyncSpiInterface;
+    use crate::interface::mock_interface::FakeDelay;
+    use crate::interface::spi::SpiControlLines;
+    use crate::interface::AsyncSensorInterface;
+    use crate::Error;
+    use core::convert::Infallible;
+    use embassy_futures::block_on;
+    use embedded_hal::digital::{self, ErrorKind, OutputPin};
+    use embedded_hal::spi::{self, Operation};
+    use embedded_hal_async::digital::Wait;
+    use embedded_hal_async::spi::SpiDevice;
+    use std::vec::Vec;
+
+    /// An SPI device that records every byte written to it
+    #[derive(Default)]
+    struct FakeSpi {
+        written: Vec<u8>,
+    }
+
+    impl spi::ErrorType for FakeSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for FakeSpi {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Self::Error> {
+            for op in operations.iter_mut() {
+                if let Operation::Write(bytes) = op {
+                    self.written.extend_from_slice(bytes);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// An output line recording every level it is driven to
+    #[derive(Default)]
+    struct FakeLine {
+        driven: Vec<bool>,
+    }
+
+    impl digital::ErrorType for FakeLine {
+        type Error = ErrorKind;
+    }
+
+    impl OutputPin for FakeLine {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.driven.push(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.driven.push(true);
+            Ok(())
+        }
+    }
+
+    /// HINTN, answering WAK with `answer`, or never if it is `None`
+    struct FakeHintn {
+        answer: Option<Result<(), ErrorKind>>,
+    }
+
+    impl digital::ErrorType for FakeHintn {
+        type Error = ErrorKind;
+    }
+
+    impl Wait for FakeHintn {
+        async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+            match self.answer {
+                Some(rc) => rc,
+                None => core::future::pending().await,
+            }
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+    }
+
+    fn interface_with_wake(
+        answer: Option<Result<(), ErrorKind>>,
+    ) -> AsyncSpiInterface<
+        FakeSpi,
+        FakeLine,
+        FakeHintn,
+        FakeLine,
+        FakeLine,
+        FakeDelay,
+    > {
+        AsyncSpiInterface::new(SpiControlLines {
+            spi: FakeSpi::default(),
+            csn: FakeLine::default(),
+            hintn: FakeHintn { answer },
+            reset: FakeLine::default(),
+        })
+        .with_wake(FakeLine::default(), FakeDelay {})
+    }
+
+    const SEND_PACKET: [u8; 6] = [6, 0, 2, 0, 0xF9, 0];
+
+    #[test]
+    fn test_write_wakes_sensor() {
+        let mut interface = interface_with_wake(Some(Ok(())));
+        assert!(block_on(interface.write_packet(&SEND_PACKET)).is_ok());
+
+        assert_eq!(interface.spi.written, SEND_PACKET);
+        let wake = interface.wake.as_ref().unwrap();
+        assert_eq!(wake.driven, [false, true]);
+    }
+
+    #[test]
+    fn test_write_to_sleeping_sensor() {
+        let mut interface = interface_with_wake(None);
+        let rc = block_on(interface.write_packet(&SEND_PACKET));
+
+        assert!(matches!(rc, Err(Error::SensorUnresponsive)));
+        assert!(interface.spi.written.is_empty());
+        let wake = interface.wake.as_ref().unwrap();
+        assert_eq!(wake.driven, [false, true]);
+    }
+
+    #[test]
+    fn test_wake_released_on_pin_error() {
+        let mut interface = interface_with_wake(Some(Err(ErrorKind::Other)));
+        let rc = block_on(interface.write_packet(&SEND_PACKET));
+
+        assert!(matches!(rc, Err(Error::Pin(ErrorKind::Other))));
+        assert!(interface.spi.written.is_empty());
+        let wake = interface.wake.as_ref().unwrap();
+        assert_eq!(wake.driven, [false, true]);
+    }
+}
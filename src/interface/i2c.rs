@@ -1,6 +1,9 @@
-use super::{SensorCommon, SensorInterface, PACKET_HEADER_LENGTH};
+use super::{
+    NoDelay, NoPin, SensorCommon, SensorInterface, PACKET_HEADER_LENGTH,
+};
 use crate::Error;
 use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{self, Error as _, InputPin, OutputPin};
 
 #[cfg(feature = "defmt-03")]
 use defmt_03 as defmt;
@@ -13,21 +16,63 @@ pub const DEFAULT_ADDRESS: u8 = 0x4A;
 /// alternate i2c address for BNO080
 pub const ALTERNATE_ADDRESS: u8 = 0x4B;
 
-/// Length of our receive buffer:
+/// Default length of our segment receive buffer:
 /// Note that this likely needs to be < 256 to accommodate underlying HAL
-const SEG_RECV_BUF_LEN: usize = 240;
-const MAX_SEGMENT_READ: usize = SEG_RECV_BUF_LEN;
+pub const SEG_RECV_BUF_LEN: usize = 240;
+/// How often HINTN is checked while waiting for data, in microseconds
+const HINTN_POLL_US: u32 = 100;
+/// How long the sensor hub may take to boot after a hardware reset,
+/// in milliseconds. Without HINTN to signal that it is ready,
+/// `setup` waits all of this time.
+const BOOT_TIMEOUT_MS: u8 = 200;
+
+/// How `I2cInterface` retries i2c transfers that fail, for example
+/// because the sensor hub did not acknowledge them, or answered with
+/// nothing but 0xFF bytes.
+/// The default is to give up at once.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    /// How many times a failed transfer is retried
+    pub retries: u8,
+    /// How long to wait before the first retry, in microseconds.
+    /// The wait doubles with every retry after that.
+    pub backoff_us: u32,
+    /// Once retries are exhausted, reset the sensor hub through
+    /// its reset line, if any
+    pub reset_on_failure: bool,
+}
 
-pub struct I2cInterface<I2C> {
+/// Communicates with the sensor hub over i2c.
+/// - HINTN: optional hardware interrupt line, which the sensor hub pulls low
+///   when it has data available. Without it, the bus is polled instead.
+/// - RSTN: optional reset line, used to reset the sensor hub in `setup`
+///
+/// Errors from either line are reported as their `digital::ErrorKind`,
+/// so that the two lines need not share an error type.
+/// - SEG_BUF_LEN: size of the buffer for segments of packets, which is also
+///   the longest read from the i2c port
+/// - D: delay source used to back off between retries
+pub struct I2cInterface<
+    I2C,
+    IN = NoPin,
+    RSTN = NoPin,
+    const SEG_BUF_LEN: usize = SEG_RECV_BUF_LEN,
+    D = NoDelay,
+> {
     /// i2c port
     i2c_port: I2C,
     /// address for i2c communications with the sensor hub
     address: u8,
     /// buffer for receiving segments of packets from the sensor hub
-    seg_recv_buf: [u8; SEG_RECV_BUF_LEN],
-
-    /// number of packets received
-    received_packet_count: usize,
+    seg_recv_buf: [u8; SEG_BUF_LEN],
+    /// line the sensor hub uses to signal that data is available
+    hintn: Option<IN>,
+    /// line used to reset the sensor hub
+    reset: Option<RSTN>,
+    /// how failed transfers are retried
+    retry_policy: RetryPolicy,
+    /// delay source used to back off between retries
+    retry_delay: D,
 }
 
 impl<I2C, CommE> I2cInterface<I2C>
@@ -47,103 +92,308 @@ where
             i2c_port: i2c,
             address: addr,
             seg_recv_buf: [0; SEG_RECV_BUF_LEN],
-            received_packet_count: 0,
+            hintn: None,
+            reset: None,
+            retry_policy: RetryPolicy::default(),
+            retry_delay: NoDelay,
+        }
+    }
+}
+
+impl<I2C, IN, RSTN, const SEG_BUF_LEN: usize, D>
+    I2cInterface<I2C, IN, RSTN, SEG_BUF_LEN, D>
+{
+    /// Segments must have room for more than a packet header
+    const SEG_BUF_LEN_CHECK: () = assert!(
+        SEG_BUF_LEN > PACKET_HEADER_LENGTH,
+        "segment buffer too small"
+    );
+
+    /// Only read from the sensor hub once it signals on its HINTN line
+    /// that data is available, rather than polling the bus
+    pub fn with_hintn<P: InputPin>(
+        self,
+        hintn: P,
+    ) -> I2cInterface<I2C, P, RSTN, SEG_BUF_LEN, D> {
+        I2cInterface {
+            i2c_port: self.i2c_port,
+            address: self.address,
+            seg_recv_buf: self.seg_recv_buf,
+            hintn: Some(hintn),
+            reset: self.reset,
+            retry_policy: self.retry_policy,
+            retry_delay: self.retry_delay,
+        }
+    }
+
+    /// Reset the sensor hub through its reset line during `setup`
+    pub fn with_reset<P: OutputPin>(
+        self,
+        reset: P,
+    ) -> I2cInterface<I2C, IN, P, SEG_BUF_LEN, D> {
+        I2cInterface {
+            i2c_port: self.i2c_port,
+            address: self.address,
+            seg_recv_buf: self.seg_recv_buf,
+            hintn: self.hintn,
+            reset: Some(reset),
+            retry_policy: self.retry_policy,
+            retry_delay: self.retry_delay,
+        }
+    }
+
+    /// Use a segment buffer of `N` bytes, rather than `SEG_RECV_BUF_LEN`.
+    /// A smaller buffer saves RAM, at the cost of more i2c reads per packet.
+    pub fn with_segment_buf_len<const N: usize>(
+        self,
+    ) -> I2cInterface<I2C, IN, RSTN, N, D> {
+        let () = I2cInterface::<I2C, IN, RSTN, N, D>::SEG_BUF_LEN_CHECK;
+        I2cInterface {
+            i2c_port: self.i2c_port,
+            address: self.address,
+            seg_recv_buf: [0; N],
+            hintn: self.hintn,
+            reset: self.reset,
+            retry_policy: self.retry_policy,
+            retry_delay: self.retry_delay,
+        }
+    }
+
+    /// Retry failed i2c transfers as `policy` allows,
+    /// using `delay` to back off between retries
+    pub fn with_retry_policy<P: DelayNs>(
+        self,
+        policy: RetryPolicy,
+        delay: P,
+    ) -> I2cInterface<I2C, IN, RSTN, SEG_BUF_LEN, P> {
+        I2cInterface {
+            i2c_port: self.i2c_port,
+            address: self.address,
+            seg_recv_buf: self.seg_recv_buf,
+            hintn: self.hintn,
+            reset: self.reset,
+            retry_policy: policy,
+            retry_delay: delay,
         }
     }
 
     pub fn free(self) -> I2C {
         self.i2c_port
     }
+}
 
-    fn read_packet_header(&mut self) -> Result<(), Error<CommE, ()>> {
-        self.zero_recv_packet_header();
-        self.i2c_port
-            .read(self.address, &mut self.seg_recv_buf[..PACKET_HEADER_LENGTH])
-            .map_err(Error::Comm)?;
+impl<I2C, IN, RSTN, CommE, const SEG_BUF_LEN: usize, D>
+    I2cInterface<I2C, IN, RSTN, SEG_BUF_LEN, D>
+where
+    I2C: embedded_hal::i2c::I2c<Error = CommE>,
+    IN: InputPin,
+    RSTN: OutputPin,
+    D: DelayNs,
+{
+    /// Is the sensor hub signalling that it has data available?
+    /// Without a HINTN line, it might be.
+    fn hintn_signaled(
+        &mut self,
+    ) -> Result<bool, Error<CommE, digital::ErrorKind>> {
+        match self.hintn.as_mut() {
+            Some(hintn) => hintn.is_low().map_err(|e| Error::Pin(e.kind())),
+            None => Ok(true),
+        }
+    }
+
+    /// Wait up to `max_ms` for the sensor hub to signal on HINTN
+    /// that it has data available.
+    /// Return true if it did, or if there is no HINTN line to wait on
+    fn wait_for_hintn(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<bool, Error<CommE, digital::ErrorKind>> {
+        let max_polls = max_ms as u32 * 1000 / HINTN_POLL_US;
+        for _ in 0..max_polls {
+            if self.hintn_signaled()? {
+                return Ok(true);
+            }
+            delay_source.delay_us(HINTN_POLL_US);
+        }
+        self.hintn_signaled()
+    }
 
+    /// Cycle the reset line, if any
+    fn hardware_reset(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), Error<CommE, digital::ErrorKind>> {
+        match self.reset.as_mut() {
+            Some(reset) => Self::cycle_reset(reset, delay_source),
+            None => Ok(()),
+        }
+    }
+
+    fn cycle_reset(
+        reset: &mut RSTN,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), Error<CommE, digital::ErrorKind>> {
+        let pin_error = |e: RSTN::Error| Error::Pin(e.kind());
+        reset.set_high().map_err(pin_error)?;
+        reset.set_low().map_err(pin_error)?;
+        delay_source.delay_ms(2);
+        reset.set_high().map_err(pin_error)?;
         Ok(())
     }
 
-    /// Read the remainder of the packet after the packet header, if any
-    fn read_sized_packet(
+    /// Run an i2c transfer, retrying it as the retry policy allows.
+    /// Once retries are exhausted, the sensor hub may be reset
+    /// before giving up.
+    fn with_retries<T>(
         &mut self,
-        total_packet_len: usize,
-        packet_recv_buf: &mut [u8],
-    ) -> Result<usize, Error<CommE, ()>> {
-        let mut remaining_body_len: usize =
-            total_packet_len - PACKET_HEADER_LENGTH;
-        let mut already_read_len: usize = 0;
+        mut transfer: impl FnMut(
+            &mut Self,
+        )
+            -> Result<T, Error<CommE, digital::ErrorKind>>,
+    ) -> Result<T, Error<CommE, digital::ErrorKind>> {
+        let mut retries_left = self.retry_policy.retries;
+        let mut backoff_us = self.retry_policy.backoff_us;
+        loop {
+            let err = match transfer(self) {
+                Ok(val) => return Ok(val),
+                Err(err) => err,
+            };
+            if retries_left == 0 {
+                if self.retry_policy.reset_on_failure {
+                    #[cfg(feature = "defmt-03")]
+                    println!("i2c retries exhausted: reset");
+                    if let Some(reset) = self.reset.as_mut() {
+                        Self::cycle_reset(reset, &mut self.retry_delay)?;
+                    }
+                }
+                return Err(err);
+            }
+            retries_left -= 1;
+            self.retry_delay.delay_us(backoff_us);
+            backoff_us = backoff_us.saturating_mul(2);
+        }
+    }
 
-        // zero packet header receive buffer
-        for byte in &mut packet_recv_buf[..PACKET_HEADER_LENGTH] {
-            *byte = 0;
+    /// Read `read_len` bytes into the segment buffer
+    fn read_segment(
+        &mut self,
+        read_len: usize,
+    ) -> Result<(), Error<CommE, digital::ErrorKind>> {
+        self.with_retries(|this| {
+            this.zero_recv_packet_header();
+            this.i2c_port
+                .read(this.address, &mut this.seg_recv_buf[..read_len])
+                .map_err(Error::Comm)?;
+            SensorCommon::check_garbage(&this.seg_recv_buf[..read_len])
+        })
+    }
+
+    fn read_packet_header(
+        &mut self,
+    ) -> Result<(), Error<CommE, digital::ErrorKind>> {
+        self.read_segment(PACKET_HEADER_LENGTH)
+    }
+
+    /// Read the rest of the packet whose header was just read, if any.
+    /// The tail of a packet we failed to read earlier is drained.
+    fn read_announced_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Error<CommE, digital::ErrorKind>> {
+        let packet_len = SensorCommon::parse_packet_header(
+            &self.seg_recv_buf[..PACKET_HEADER_LENGTH],
+        );
+        if packet_len <= PACKET_HEADER_LENGTH {
+            return Ok(packet_len);
+        }
+        if SensorCommon::is_continuation(
+            &self.seg_recv_buf[..PACKET_HEADER_LENGTH],
+        ) {
+            #[cfg(feature = "defmt-03")]
+            println!("orphan segment {}", packet_len);
+            self.read_sized_packet(packet_len, &mut [])?;
+            return Err(Error::OrphanSegment);
         }
+        self.read_sized_packet(packet_len, recv_buf)
+    }
 
-        if total_packet_len < MAX_SEGMENT_READ {
+    /// Read the packet whose header was just read into the segment buffer.
+    /// The sensor hub sends it again from its original header,
+    /// in one or more segments: each segment after the first has
+    /// a header of its own continuing the packet.
+    /// Returns the whole packet length, even when the packet
+    /// did not fit in `packet_recv_buf`.
+    fn read_sized_packet(
+        &mut self,
+        total_packet_len: usize,
+        packet_recv_buf: &mut [u8],
+    ) -> Result<usize, Error<CommE, digital::ErrorKind>> {
+        let mut packet_header = [0u8; PACKET_HEADER_LENGTH];
+        packet_header
+            .copy_from_slice(&self.seg_recv_buf[..PACKET_HEADER_LENGTH]);
+        let mut segment_seq = packet_header[3];
+
+        if total_packet_len < SEG_BUF_LEN
+            && total_packet_len <= packet_recv_buf.len()
+        {
             //read directly into the provided receive buffer
-            if total_packet_len > 0 {
-                self.i2c_port
-                    .read(
-                        self.address,
-                        &mut packet_recv_buf[..total_packet_len],
-                    )
-                    .map_err(Error::Comm)?;
-                already_read_len = total_packet_len;
-            }
-        } else {
-            while remaining_body_len > 0 {
-                let whole_segment_length =
-                    remaining_body_len + PACKET_HEADER_LENGTH;
-                let segment_read_len =
-                    if whole_segment_length > MAX_SEGMENT_READ {
-                        MAX_SEGMENT_READ
-                    } else {
-                        whole_segment_length
-                    };
-
-                self.zero_recv_packet_header();
-                self.i2c_port
-                    .read(
-                        self.address,
-                        &mut self.seg_recv_buf[..segment_read_len],
-                    )
+            let read_buf = &mut packet_recv_buf[..total_packet_len];
+            self.with_retries(|this| {
+                this.i2c_port
+                    .read(this.address, read_buf)
                     .map_err(Error::Comm)?;
+                SensorCommon::check_garbage(read_buf)
+            })?;
+            SensorCommon::check_restart_header(
+                &packet_header,
+                &packet_recv_buf[..PACKET_HEADER_LENGTH],
+            )?;
+            return Ok(total_packet_len);
+        }
 
-                let promised_packet_len = SensorCommon::parse_packet_header(
-                    &self.seg_recv_buf[..PACKET_HEADER_LENGTH],
-                );
-                if promised_packet_len <= PACKET_HEADER_LENGTH {
-                    #[cfg(feature = "defmt-03")]
-                    println!("WTFFF {}", promised_packet_len);
-                    return Ok(0);
-                }
+        let mut remaining_body_len = total_packet_len - PACKET_HEADER_LENGTH;
+        let mut already_read_len = PACKET_HEADER_LENGTH;
+        while remaining_body_len > 0 {
+            let segment_read_len =
+                (remaining_body_len + PACKET_HEADER_LENGTH).min(SEG_BUF_LEN);
+
+            self.read_segment(segment_read_len)?;
+            let segment_header = &self.seg_recv_buf[..PACKET_HEADER_LENGTH];
+            if already_read_len == PACKET_HEADER_LENGTH {
+                SensorCommon::check_restart_header(
+                    &packet_header,
+                    segment_header,
+                )?;
+            } else {
+                segment_seq = SensorCommon::check_segment_header(
+                    &packet_header,
+                    segment_seq,
+                    segment_header,
+                )?;
+            }
 
-                //if we've never read any segments, transcribe the first packet header;
-                //otherwise, just transcribe the segment body (no header)
-                let transcribe_start_idx = if already_read_len > 0 {
-                    PACKET_HEADER_LENGTH
-                } else {
-                    0
-                };
-                let transcribe_len = if already_read_len > 0 {
-                    segment_read_len - PACKET_HEADER_LENGTH
-                } else {
-                    segment_read_len
-                };
-                packet_recv_buf
-                    [already_read_len..already_read_len + transcribe_len]
+            // only the body of each segment is transcribed:
+            // a packet too large for the receive buffer is drained,
+            // keeping only its leading part
+            let body_read_len = segment_read_len - PACKET_HEADER_LENGTH;
+            let kept_len = body_read_len
+                .min(packet_recv_buf.len().saturating_sub(already_read_len));
+            if kept_len > 0 {
+                packet_recv_buf[already_read_len..already_read_len + kept_len]
                     .copy_from_slice(
-                        &self.seg_recv_buf[transcribe_start_idx
-                            ..transcribe_start_idx + transcribe_len],
+                        &self.seg_recv_buf[PACKET_HEADER_LENGTH
+                            ..PACKET_HEADER_LENGTH + kept_len],
                     );
-                already_read_len += transcribe_len;
-
-                let body_read_len = segment_read_len - PACKET_HEADER_LENGTH;
-                remaining_body_len -= body_read_len;
             }
+            already_read_len += body_read_len;
+            remaining_body_len -= body_read_len;
         }
 
-        Ok(already_read_len)
+        let header_len = packet_recv_buf.len().min(PACKET_HEADER_LENGTH);
+        packet_recv_buf[..header_len]
+            .copy_from_slice(&packet_header[..header_len]);
+        Ok(total_packet_len)
     }
 
     fn zero_recv_packet_header(&mut self) {
@@ -151,17 +401,21 @@ where
     }
 
     fn zero_buffer(buf: &mut [u8]) {
-        for byte in buf.as_mut() {
+        for byte in buf {
             *byte = 0;
         }
     }
 }
 
-impl<I2C, CommE> SensorInterface for I2cInterface<I2C>
+impl<I2C, IN, RSTN, CommE, const SEG_BUF_LEN: usize, D> SensorInterface
+    for I2cInterface<I2C, IN, RSTN, SEG_BUF_LEN, D>
 where
     I2C: embedded_hal::i2c::I2c<Error = CommE>,
+    IN: InputPin,
+    RSTN: OutputPin,
+    D: DelayNs,
 {
-    type SensorError = Error<CommE, ()>;
+    type SensorError = Error<CommE, digital::ErrorKind>;
 
     fn requires_soft_reset(&self) -> bool {
         true
@@ -173,17 +427,34 @@ where
     ) -> Result<(), Self::SensorError> {
         // #[cfg(feature = "defmt-03")]
         // println!("i2c setup");
+        self.hardware_reset(delay_source)?;
         delay_source.delay_ms(5);
+        if self.reset.is_none() {
+            return Ok(());
+        }
+        if self.hintn.is_none() {
+            // nothing will signal that the sensor hub is ready:
+            // give it all the time it may need to boot
+            delay_source.delay_ms(BOOT_TIMEOUT_MS as u32);
+            return Ok(());
+        }
+        // the sensor hub signals once it is ready after a reset
+        if !self.wait_for_hintn(delay_source, BOOT_TIMEOUT_MS)? {
+            #[cfg(feature = "defmt-03")]
+            println!("sensor not ready");
+            return Err(Error::SensorUnresponsive);
+        }
         Ok(())
     }
 
     fn write_packet(&mut self, packet: &[u8]) -> Result<(), Self::SensorError> {
         #[cfg(feature = "defmt-03")]
         println!("w {:x} {}", self.address, packet.len());
-        self.i2c_port
-            .write(self.address, &packet)
-            .map_err(Error::Comm)?;
-        Ok(())
+        self.with_retries(|this| {
+            this.i2c_port
+                .write(this.address, packet)
+                .map_err(Error::Comm)
+        })
     }
 
     fn read_with_timeout(
@@ -192,6 +463,14 @@ where
         delay_source: &mut impl DelayNs,
         max_ms: u8,
     ) -> Result<usize, Self::SensorError> {
+        if self.hintn.is_some() {
+            // no need to poll the bus: read once the sensor hub signals
+            if self.wait_for_hintn(delay_source, max_ms)? {
+                return self.read_packet(recv_buf);
+            }
+            return Ok(0);
+        }
+
         let mut total_delay: u8 = 0;
         while total_delay < max_ms {
             match self.read_packet(recv_buf) {
@@ -220,27 +499,7 @@ where
         // println!("rpkt");
 
         self.read_packet_header()?;
-        let packet_len = SensorCommon::parse_packet_header(
-            &self.seg_recv_buf[..PACKET_HEADER_LENGTH],
-        );
-
-        // if packet_len == 0 {
-        //     #[cfg(feature = "defmt-03")]
-        //     println!("eh {:x?}", &self.seg_recv_buf[..PACKET_HEADER_LENGTH]);
-        // }
-
-        let received_len = if packet_len > PACKET_HEADER_LENGTH {
-            self.read_sized_packet(packet_len, recv_buf)?
-        } else {
-            packet_len
-        };
-
-        if packet_len > 0 {
-            self.received_packet_count += 1;
-            //let _ = SensorCommon::parse_packet_header(&recv_buf[..packet_len]);
-        }
-
-        Ok(received_len)
+        self.read_announced_packet(recv_buf)
     }
 
     fn send_and_receive_packet(
@@ -250,43 +509,340 @@ where
     ) -> Result<usize, Self::SensorError> {
         // Cannot use write_read with bno080,
         // because it does not support repeated start with i2c.
+        self.write_packet(send_buf)?;
 
-        self.i2c_port
-            .write(self.address, send_buf)
-            .map_err(Error::Comm)?;
-
-        self.zero_recv_packet_header();
         //stall before attempted read?
         Self::zero_buffer(recv_buf);
 
-        self.i2c_port
-            .read(self.address, &mut self.seg_recv_buf[..PACKET_HEADER_LENGTH])
-            .map_err(Error::Comm)?;
-
-        let packet_len = SensorCommon::parse_packet_header(
-            &self.seg_recv_buf[..PACKET_HEADER_LENGTH],
-        );
-
-        let received_len = if packet_len > PACKET_HEADER_LENGTH {
-            self.read_sized_packet(packet_len, recv_buf)?
-        } else {
-            packet_len
-        };
-        if packet_len > 0 {
-            self.received_packet_count += 1;
-        }
-
-        Ok(received_len)
+        self.read_packet_header()?;
+        self.read_announced_packet(recv_buf)
     }
 }
 
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
+    extern crate std;
+
     use crate::interface::i2c::DEFAULT_ADDRESS;
     // use crate::interface::mock_i2c_port::FakeI2cPort;
-    use crate::interface::I2cInterface;
+    use crate::interface::mock_interface::{FakeDelay, FakePin};
+    use crate::interface::PACKET_HEADER_LENGTH;
+    use crate::interface::{I2cInterface, RetryPolicy, SensorInterface};
     use crate::wrapper::BNO080;
+    use crate::Error;
+    use embedded_hal::digital;
+    use embedded_hal::i2c::{self, ErrorKind, Operation};
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// An i2c port behaving like the sensor hub: every read transfers
+    /// the next part of the packet waiting to be read, after a header
+    /// giving the length left to send, the continuation flag and
+    /// the next sequence number of its channel.
+    /// A read too short to take any of the body leaves the packet
+    /// to be sent again from its original header.
+    #[derive(Default)]
+    struct FakeI2c {
+        available_packets: VecDeque<Vec<u8>>,
+        /// body bytes of the first packet already transferred
+        sent_len: usize,
+        /// transfers of the first packet so far
+        transfers: u8,
+        /// the read at which the first packet is abandoned, if any
+        abandon_at_read: Option<usize>,
+        written: Vec<Vec<u8>>,
+        read_count: usize,
+        /// the number of transfers still to be refused
+        nak_count: usize,
+        /// the number of reads still to return only 0xFF bytes
+        garbage_count: usize,
+    }
+
+    impl FakeI2c {
+        fn read(&mut self, buf: &mut [u8]) {
+            self.read_count += 1;
+            buf.fill(0);
+            if self.abandon_at_read == Some(self.read_count) {
+                self.next_packet();
+            }
+            let packet = match self.available_packets.front() {
+                Some(packet) => packet,
+                None => return,
+            };
+
+            let body = &packet[PACKET_HEADER_LENGTH..];
+            let remaining_len = body.len() - self.sent_len;
+            let segment_len = remaining_len + PACKET_HEADER_LENGTH;
+            let mut header = [
+                segment_len as u8,
+                (segment_len >> 8) as u8,
+                packet[2],
+                packet[3].wrapping_add(self.transfers),
+            ];
+            if self.transfers > 0 {
+                header[1] |= 0x80;
+            }
+            let header_len = buf.len().min(PACKET_HEADER_LENGTH);
+            buf[..header_len].copy_from_slice(&header[..header_len]);
+
+            let body_len = buf
+                .len()
+                .saturating_sub(PACKET_HEADER_LENGTH)
+                .min(remaining_len);
+            buf[PACKET_HEADER_LENGTH..PACKET_HEADER_LENGTH + body_len]
+                .copy_from_slice(
+                    &body[self.sent_len..self.sent_len + body_len],
+                );
+            if body_len == 0 {
+                return;
+            }
+            self.sent_len += body_len;
+            self.transfers += 1;
+            if self.sent_len == body.len() {
+                self.next_packet();
+            }
+        }
+
+        fn next_packet(&mut self) {
+            self.available_packets.pop_front();
+            self.sent_len = 0;
+            self.transfers = 0;
+        }
+    }
+
+    impl i2c::ErrorType for FakeI2c {
+        type Error = ErrorKind;
+    }
+
+    impl i2c::I2c for FakeI2c {
+        fn transaction(
+            &mut self,
+            _address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if self.nak_count > 0 {
+                self.nak_count -= 1;
+                return Err(ErrorKind::Other);
+            }
+            for op in operations {
+                match op {
+                    Operation::Read(buf) if self.garbage_count > 0 => {
+                        self.garbage_count -= 1;
+                        buf.fill(0xFF);
+                    }
+                    Operation::Read(buf) => self.read(buf),
+                    Operation::Write(bytes) => {
+                        self.written.push(bytes.to_vec())
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_on_hintn() {
+        let mut port = FakeI2c::default();
+        port.available_packets
+            .push_back([6, 0, 2, 0, 0xEF, 5].to_vec());
+        let mut i2c =
+            I2cInterface::default(port).with_hintn(FakePin::default());
+        let mut recv_buf = [0u8; 32];
+
+        // the bus is left alone until the sensor hub signals
+        let rc = i2c.read_with_timeout(&mut recv_buf, &mut FakeDelay {}, 5);
+        assert_eq!(rc.ok(), Some(0));
+        assert_eq!(i2c.i2c_port.read_count, 0);
+
+        i2c.hintn.as_mut().unwrap().low = true;
+        let rc = i2c.read_with_timeout(&mut recv_buf, &mut FakeDelay {}, 5);
+        assert_eq!(rc.ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 0, 0xEF, 5]);
+    }
+
+    #[test]
+    fn test_setup_resets() {
+        let mut i2c = I2cInterface::default(FakeI2c::default())
+            .with_reset(FakePin::default());
+        assert!(i2c.setup(&mut FakeDelay {}).is_ok());
+        assert_eq!(i2c.reset.unwrap().driven, [true, false, true]);
+
+        // with HINTN, the sensor hub must signal that it is ready
+        let mut i2c = I2cInterface::default(FakeI2c::default())
+            .with_hintn(FakePin::default())
+            .with_reset(FakePin::default());
+        assert!(i2c.setup(&mut FakeDelay {}).is_err());
+    }
+
+    /// A control line whose every use fails
+    struct BrokenPin;
+
+    impl digital::ErrorType for BrokenPin {
+        type Error = digital::ErrorKind;
+    }
+
+    impl digital::InputPin for BrokenPin {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Err(digital::ErrorKind::Other)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Err(digital::ErrorKind::Other)
+        }
+    }
+
+    impl digital::OutputPin for BrokenPin {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            Err(digital::ErrorKind::Other)
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            Err(digital::ErrorKind::Other)
+        }
+    }
+
+    #[test]
+    fn test_pin_errors() {
+        let mut i2c =
+            I2cInterface::default(FakeI2c::default()).with_hintn(BrokenPin);
+        let mut recv_buf = [0u8; 32];
+        let rc = i2c.read_with_timeout(&mut recv_buf, &mut FakeDelay {}, 5);
+        assert!(matches!(rc, Err(Error::Pin(digital::ErrorKind::Other))));
+
+        let mut i2c = I2cInterface::default(FakeI2c::default())
+            .with_hintn(FakePin::default())
+            .with_reset(BrokenPin);
+        let rc = i2c.setup(&mut FakeDelay {});
+        assert!(matches!(rc, Err(Error::Pin(digital::ErrorKind::Other))));
+    }
+
+    #[test]
+    fn test_drain_oversized_packet() {
+        let mut oversized = [0xA5u8; 40];
+        oversized[..4].copy_from_slice(&[40, 0, 2, 0]);
+        let mut port = FakeI2c::default();
+        port.available_packets.push_back(oversized.to_vec());
+        port.available_packets
+            .push_back([6, 0, 2, 1, 0xEF, 5].to_vec());
+        let mut i2c = I2cInterface::default(port);
+
+        // the whole length is reported, keeping what fits
+        let mut recv_buf = [0u8; 20];
+        assert_eq!(i2c.read_packet(&mut recv_buf).ok(), Some(40));
+        assert_eq!(recv_buf, oversized[..20]);
+        // the rest was drained: the next read gets the next packet
+        assert_eq!(i2c.read_packet(&mut recv_buf).ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 1, 0xEF, 5]);
+    }
+
+    #[test]
+    fn test_multi_segment_packet() {
+        let packet: Vec<u8> =
+            [40, 0, 3, 7].iter().copied().chain(0..36).collect();
+        let mut port = FakeI2c::default();
+        port.available_packets.push_back(packet.clone());
+        let mut i2c = I2cInterface::default(port).with_segment_buf_len::<16>();
+
+        let mut recv_buf = [0u8; 64];
+        assert_eq!(i2c.read_packet(&mut recv_buf).ok(), Some(40));
+        assert_eq!(recv_buf[..40], packet[..]);
+        // the header, then three segments of 12 bytes each
+        assert_eq!(i2c.i2c_port.read_count, 4);
+    }
+
+    #[test]
+    fn test_recover_from_partial_packet() {
+        let mut abandoned = [0u8; 40];
+        abandoned[..4].copy_from_slice(&[40, 0, 2, 3]);
+        let mut port = FakeI2c::default();
+        port.available_packets.push_back(abandoned.to_vec());
+        let mut partial = [0u8; 30];
+        partial[..4].copy_from_slice(&[30, 0, 2, 5]);
+        port.available_packets.push_back(partial.to_vec());
+        port.available_packets
+            .push_back([6, 0, 2, 6, 0xEF, 5].to_vec());
+        // the sensor hub moves on to the next packet midway
+        port.abandon_at_read = Some(3);
+        let mut i2c = I2cInterface::default(port).with_segment_buf_len::<16>();
+
+        let mut recv_buf = [0u8; 64];
+        let rc = i2c.read_packet(&mut recv_buf);
+        assert!(matches!(rc, Err(Error::SegmentNotContinued)));
+        // the rest of the next packet is drained
+        let rc = i2c.read_packet(&mut recv_buf);
+        assert!(matches!(rc, Err(Error::OrphanSegment)));
+        assert_eq!(i2c.read_packet(&mut recv_buf).ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 6, 0xEF, 5]);
+    }
+
+    #[test]
+    fn test_retry_transfers() {
+        let mut port = FakeI2c::default();
+        port.available_packets
+            .push_back([6, 0, 2, 0, 0xEF, 5].to_vec());
+        port.nak_count = 2;
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff_us: 100,
+            reset_on_failure: false,
+        };
+        let mut i2c =
+            I2cInterface::default(port).with_retry_policy(policy, FakeDelay {});
+
+        let mut recv_buf = [0u8; 32];
+        assert_eq!(i2c.read_packet(&mut recv_buf).ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 0, 0xEF, 5]);
+
+        i2c.i2c_port.nak_count = 2;
+        assert!(i2c.write_packet(&[5, 0, 2, 0, 0xF9]).is_ok());
+        assert_eq!(i2c.i2c_port.written, [[5, 0, 2, 0, 0xF9]]);
+    }
+
+    #[test]
+    fn test_reset_after_retries_exhausted() {
+        let port = FakeI2c {
+            nak_count: 3,
+            ..Default::default()
+        };
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff_us: 100,
+            reset_on_failure: true,
+        };
+        let mut i2c = I2cInterface::default(port)
+            .with_reset(FakePin::default())
+            .with_retry_policy(policy, FakeDelay {});
+
+        let rc = i2c.write_packet(&[5, 0, 2, 0, 0xF9]);
+        assert!(matches!(rc, Err(Error::Comm(ErrorKind::Other))));
+        assert_eq!(i2c.reset.unwrap().driven, [true, false, true]);
+    }
+
+    #[test]
+    fn test_garbage_header() {
+        let mut port = FakeI2c::default();
+        port.available_packets
+            .push_back([6, 0, 2, 0, 0xEF, 5].to_vec());
+        port.garbage_count = 1;
+        let mut i2c = I2cInterface::default(port);
+
+        let mut recv_buf = [0u8; 32];
+        let rc = i2c.read_packet(&mut recv_buf);
+        assert!(matches!(rc, Err(Error::GarbageHeader)));
+
+        // a retry reads the header again
+        i2c.i2c_port.garbage_count = 1;
+        let mut i2c = i2c.with_retry_policy(
+            RetryPolicy {
+                retries: 1,
+                ..Default::default()
+            },
+            FakeDelay {},
+        );
+        assert_eq!(i2c.read_packet(&mut recv_buf).ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 0, 0xEF, 5]);
+    }
 
     // #[test]
     // fn test_multi_segment_receive_packet() {
@@ -0,0 +1,267 @@
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+
+/// Default i2c address of the TCA9548A multiplexer
+pub const TCA9548A_DEFAULT_ADDRESS: u8 = 0x70;
+/// Number of downstream channels of the TCA9548A multiplexer
+pub const TCA9548A_CHANNELS: u8 = 8;
+
+/// A downstream channel of the TCA9548A multiplexer, 0 to 7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MuxChannel(u8);
+
+impl MuxChannel {
+    /// Channel `index`, if the multiplexer has such a channel
+    pub const fn new(index: u8) -> Option<Self> {
+        if index < TCA9548A_CHANNELS {
+            Some(Self(index))
+        } else {
+            None
+        }
+    }
+
+    /// Every channel of the multiplexer, in order
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..TCA9548A_CHANNELS).map(Self)
+    }
+
+    /// Number of the channel, 0 to 7
+    pub const fn index(self) -> u8 {
+        self.0
+    }
+}
+
+/// One downstream channel of an i2c multiplexer such as the TCA9548A,
+/// which connects its upstream bus to the channels enabled by the control
+/// byte last written to it, one bit per channel.
+///
+/// Every transaction first selects this channel alone, so that sensor hubs
+/// sharing an address can each sit on their own channel. To give every
+/// channel its own `I2cInterface`, pass each `I2cMuxChannel` a shared device
+/// for the upstream bus, such as those of `embedded-hal-bus`.
+/// The channel is selected and used in two separate transactions:
+/// the bus must not be shared across threads or interrupt priorities
+/// without a lock held around both.
+pub struct I2cMuxChannel<I2C> {
+    /// upstream i2c bus
+    bus: I2C,
+    /// address of the multiplexer
+    mux_address: u8,
+    /// control byte selecting this channel alone
+    select: u8,
+}
+
+impl<I2C> I2cMuxChannel<I2C> {
+    /// Use `channel` of the multiplexer at `mux_address`
+    pub fn new(bus: I2C, mux_address: u8, channel: MuxChannel) -> Self {
+        Self {
+            bus,
+            mux_address,
+            select: 1 << channel.index(),
+        }
+    }
+
+    /// Use `channel` of a TCA9548A at its default address
+    pub fn tca9548a(bus: I2C, channel: MuxChannel) -> Self {
+        Self::new(bus, TCA9548A_DEFAULT_ADDRESS, channel)
+    }
+
+    /// Give back the upstream bus
+    pub fn release(self) -> I2C {
+        self.bus
+    }
+}
+
+impl<I2C: ErrorType> ErrorType for I2cMuxChannel<I2C> {
+    type Error = I2C::Error;
+}
+
+impl<I2C: I2c> I2c for I2cMuxChannel<I2C> {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.bus.write(self.mux_address, &[self.select])?;
+        self.bus.transaction(address, operations)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C: embedded_hal_async::i2c::I2c> embedded_hal_async::i2c::I2c
+    for I2cMuxChannel<I2C>
+{
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.bus.write(self.mux_address, &[self.select]).await?;
+        self.bus.transaction(address, operations).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{
+        I2cMuxChannel, MuxChannel, TCA9548A_CHANNELS, TCA9548A_DEFAULT_ADDRESS,
+    };
+    use crate::interface::i2c::{ALTERNATE_ADDRESS, DEFAULT_ADDRESS};
+    use crate::interface::{I2cInterface, SensorInterface};
+    use core::cell::RefCell;
+    use embedded_hal::i2c::{self, ErrorKind, NoAcknowledgeSource, Operation};
+    use embedded_hal_bus::i2c::RefCellDevice;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// A sensor hub sending packets short enough to be read whole:
+    /// a read that stops short, such as that of the header alone,
+    /// leaves the packet to be sent again from its header
+    struct FakeHub {
+        /// mux channel the hub sits on, if any
+        channel: Option<u8>,
+        address: u8,
+        available_packets: VecDeque<Vec<u8>>,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl FakeHub {
+        fn read(&mut self, buf: &mut [u8]) {
+            buf.fill(0);
+            let packet = match self.available_packets.front() {
+                Some(packet) => packet,
+                None => return,
+            };
+            let read_len = buf.len().min(packet.len());
+            buf[..read_len].copy_from_slice(&packet[..read_len]);
+            if read_len == packet.len() {
+                self.available_packets.pop_front();
+            }
+        }
+    }
+
+    /// An i2c bus shared by sensor hubs, some of them behind a TCA9548A
+    #[derive(Default)]
+    struct FakeBus {
+        /// control byte last written to the mux
+        selected: u8,
+        hubs: Vec<FakeHub>,
+        /// addresses of the transactions so far
+        transactions: Vec<u8>,
+    }
+
+    impl FakeBus {
+        fn add_hub(&mut self, channel: Option<u8>, address: u8, packet: &[u8]) {
+            self.hubs.push(FakeHub {
+                channel,
+                address,
+                available_packets: [packet.to_vec()].into(),
+                written: Vec::new(),
+            });
+        }
+
+        /// The hub answering at `address`, if any
+        fn hub(&mut self, address: u8) -> Option<&mut FakeHub> {
+            let selected = self.selected;
+            let mut hubs = self.hubs.iter_mut().filter(|hub| {
+                hub.address == address
+                    && hub.channel.is_none_or(|ch| selected & (1 << ch) != 0)
+            });
+            let hub = hubs.next();
+            assert!(hubs.next().is_none(), "address conflict on the bus");
+            hub
+        }
+    }
+
+    impl i2c::ErrorType for FakeBus {
+        type Error = ErrorKind;
+    }
+
+    impl i2c::I2c for FakeBus {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            self.transactions.push(address);
+            if address == TCA9548A_DEFAULT_ADDRESS {
+                if let [Operation::Write(&[select])] = operations {
+                    self.selected = select;
+                }
+                return Ok(());
+            }
+            let hub = self.hub(address).ok_or(ErrorKind::NoAcknowledge(
+                NoAcknowledgeSource::Address,
+            ))?;
+            for op in operations {
+                match op {
+                    Operation::Read(buf) => hub.read(buf),
+                    Operation::Write(bytes) => hub.written.push(bytes.to_vec()),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shared_bus() {
+        let mut bus = FakeBus::default();
+        bus.add_hub(None, DEFAULT_ADDRESS, &[6, 0, 2, 0, 0xEF, 1]);
+        bus.add_hub(None, ALTERNATE_ADDRESS, &[6, 0, 2, 0, 0xEF, 2]);
+        let bus = RefCell::new(bus);
+        let mut hub_a = I2cInterface::default(RefCellDevice::new(&bus));
+        let mut hub_b = I2cInterface::alternate(RefCellDevice::new(&bus));
+
+        let mut recv_buf = [0u8; 16];
+        assert_eq!(hub_b.read_packet(&mut recv_buf).ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 0, 0xEF, 2]);
+        assert_eq!(hub_a.read_packet(&mut recv_buf).ok(), Some(6));
+        assert_eq!(recv_buf[..6], [6, 0, 2, 0, 0xEF, 1]);
+    }
+
+    #[test]
+    fn test_mux_channels() {
+        let mut bus = FakeBus::default();
+        for channel in 0..3 {
+            bus.add_hub(
+                Some(channel),
+                DEFAULT_ADDRESS,
+                &[6, 0, 2, 0, 0xEF, channel],
+            );
+        }
+        let bus = RefCell::new(bus);
+        let mut hubs: Vec<_> = MuxChannel::all()
+            .take(3)
+            .map(|channel| {
+                I2cInterface::default(I2cMuxChannel::tca9548a(
+                    RefCellDevice::new(&bus),
+                    channel,
+                ))
+            })
+            .collect();
+
+        let mut recv_buf = [0u8; 16];
+        for channel in [2, 0, 1] {
+            let hub = &mut hubs[channel as usize];
+            assert_eq!(hub.read_packet(&mut recv_buf).ok(), Some(6));
+            assert_eq!(recv_buf[..6], [6, 0, 2, 0, 0xEF, channel]);
+        }
+        assert!(hubs[1].write_packet(&[5, 0, 2, 1, 0xF9]).is_ok());
+
+        let bus = bus.into_inner();
+        assert_eq!(bus.hubs[1].written, [[5, 0, 2, 1, 0xF9]]);
+        assert!(bus.hubs[0].written.is_empty());
+        // the mux channel is selected before every transaction
+        assert!(bus.transactions.chunks(2).all(|pair| {
+            pair == [TCA9548A_DEFAULT_ADDRESS, DEFAULT_ADDRESS]
+        }));
+    }
+
+    #[test]
+    fn test_mux_channel_range() {
+        assert_eq!(MuxChannel::new(7).map(MuxChannel::index), Some(7));
+        assert_eq!(MuxChannel::new(TCA9548A_CHANNELS), None);
+        assert_eq!(MuxChannel::all().count(), TCA9548A_CHANNELS as usize);
+    }
+}
@@ -0,0 +1,184 @@
+extern crate std;
+
+use super::SensorInterface;
+use core::convert::Infallible;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{self, InputPin, OutputPin};
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+pub struct FakeDelay {}
+
+impl DelayNs for FakeDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+#[cfg(feature = "async")]
+impl embedded_hal_async::delay::DelayNs for FakeDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// A control line whose level is set by the test,
+/// recording every level it is driven to
+#[derive(Default)]
+pub struct FakePin {
+    pub low: bool,
+    pub driven: Vec<bool>,
+}
+
+impl digital::ErrorType for FakePin {
+    type Error = Infallible;
+}
+
+impl InputPin for FakePin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.low)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.low)
+    }
+}
+
+impl OutputPin for FakePin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.driven.push(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.driven.push(true);
+        Ok(())
+    }
+}
+
+/// A sensor interface that replays canned packets and records
+/// every packet written to it
+#[derive(Clone, Default)]
+pub struct FakeSensorInterface {
+    pub available_packets: VecDeque<Vec<u8>>,
+    pub sent_packets: Vec<Vec<u8>>,
+    /// number of times `flush_outbound` was called
+    pub flushes: usize,
+}
+
+impl FakeSensorInterface {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enqueue a packet to be received later
+    pub fn add_available_packet(&mut self, bytes: &[u8]) {
+        self.available_packets.push_back(bytes.to_vec());
+    }
+}
+
+impl SensorInterface for FakeSensorInterface {
+    type SensorError = ();
+
+    fn setup(
+        &mut self,
+        _delay_source: &mut impl DelayNs,
+    ) -> Result<(), Self::SensorError> {
+        Ok(())
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> Result<(), Self::SensorError> {
+        self.sent_packets.push(packet.to_vec());
+        Ok(())
+    }
+
+    fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        match self.available_packets.pop_front() {
+            Some(packet) => {
+                // like the real interfaces, report the whole packet length
+                let len = packet.len().min(recv_buf.len());
+                recv_buf[..len].copy_from_slice(&packet[..len]);
+                Ok(packet.len())
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        _delay_source: &mut impl DelayNs,
+        _max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        self.read_packet(recv_buf)
+    }
+
+    fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        self.write_packet(send_buf)?;
+        self.read_packet(recv_buf)
+    }
+
+    fn flush_outbound(&mut self) -> Result<(), Self::SensorError> {
+        self.flushes += 1;
+        Ok(())
+    }
+
+    fn requires_soft_reset(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "async")]
+impl super::AsyncSensorInterface for FakeSensorInterface {
+    type SensorError = ();
+
+    async fn setup(
+        &mut self,
+        _delay_source: &mut impl embedded_hal_async::delay::DelayNs,
+    ) -> Result<(), Self::SensorError> {
+        Ok(())
+    }
+
+    async fn write_packet(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Self::SensorError> {
+        SensorInterface::write_packet(self, packet)
+    }
+
+    async fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        SensorInterface::read_packet(self, recv_buf)
+    }
+
+    async fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        _delay_source: &mut impl embedded_hal_async::delay::DelayNs,
+        _max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        SensorInterface::read_packet(self, recv_buf)
+    }
+
+    async fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+        _delay_source: &mut impl embedded_hal_async::delay::DelayNs,
+    ) -> Result<usize, Self::SensorError> {
+        SensorInterface::send_and_receive_packet(self, send_buf, recv_buf)
+    }
+
+    async fn flush_outbound(&mut self) -> Result<(), Self::SensorError> {
+        SensorInterface::flush_outbound(self)
+    }
+
+    fn requires_soft_reset(&self) -> bool {
+        true
+    }
+}
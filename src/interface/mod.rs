@@ -1,12 +1,25 @@
+#[cfg(feature = "async")]
+pub mod async_i2c;
+#[cfg(feature = "async")]
+pub mod async_spi;
 pub mod i2c;
+pub mod i2c_mux;
+pub mod rvc;
 pub mod spi;
+pub mod uart;
 
 #[cfg(test)]
 pub mod mock_i2c_port;
+#[cfg(test)]
+pub mod mock_interface;
 
+use core::convert::Infallible;
+use core::marker::PhantomData;
 use core::ops::Shl;
 
+use crate::Error;
 use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{self, ErrorType, InputPin, OutputPin};
 
 #[cfg(feature = "defmt-03")]
 use defmt_03 as defmt;
@@ -29,7 +42,8 @@ pub trait SensorInterface {
     fn write_packet(&mut self, packet: &[u8]) -> Result<(), Self::SensorError>;
 
     /// Read the next packet from the sensor
-    /// Returns the size of the packet read (up to the size of the slice provided)
+    /// Returns the size of the packet read. A packet larger than the slice
+    /// provided is drained, keeping only its leading part.
     fn read_packet(
         &mut self,
         recv_buf: &mut [u8],
@@ -51,19 +65,239 @@ pub trait SensorInterface {
         recv_buf: &mut [u8],
     ) -> Result<usize, Self::SensorError>;
 
+    /// Send any packets the interface has held back,
+    /// for interfaces that send packets along with those they read
+    fn flush_outbound(&mut self) -> Result<(), Self::SensorError> {
+        Ok(())
+    }
+
     /// Does this interface require a soft reset after init?
     fn requires_soft_reset(&self) -> bool;
 }
 
-pub use self::i2c::I2cInterface;
+/// A method of communicating with the sensor that awaits, rather than
+/// blocks, whenever the sensor is not ready
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncSensorInterface {
+    /// Interface error type
+    type SensorError;
+
+    /// give the sensor interface a chance to set up
+    async fn setup(
+        &mut self,
+        delay_source: &mut impl embedded_hal_async::delay::DelayNs,
+    ) -> Result<(), Self::SensorError>;
+
+    /// Write the whole packet provided
+    async fn write_packet(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Self::SensorError>;
+
+    /// Read the next packet from the sensor
+    /// Returns the size of the packet read. A packet larger than the slice
+    /// provided is drained, keeping only its leading part.
+    async fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError>;
+
+    /// Wait for sensor to indicate it has data available before reading
+    /// - `max_ms` maximum number of milliseconds to wait for data
+    async fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        delay_source: &mut impl embedded_hal_async::delay::DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, Self::SensorError>;
+
+    /// Send a packet and receive the response immediately,
+    /// using `delay_source` to give up if no response is ready
+    async fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+        delay_source: &mut impl embedded_hal_async::delay::DelayNs,
+    ) -> Result<usize, Self::SensorError>;
+
+    /// Send any packets the interface has held back,
+    /// for interfaces that send packets along with those they read
+    async fn flush_outbound(&mut self) -> Result<(), Self::SensorError> {
+        Ok(())
+    }
+
+    /// Does this interface require a soft reset after init?
+    fn requires_soft_reset(&self) -> bool;
+}
+
+#[cfg(feature = "async")]
+pub use self::async_i2c::AsyncI2cInterface;
+#[cfg(feature = "async")]
+pub use self::async_spi::AsyncSpiInterface;
+pub use self::i2c::{I2cInterface, RetryPolicy};
+pub use self::i2c_mux::{I2cMuxChannel, MuxChannel};
+pub use self::rvc::{RvcReceiver, RvcSample};
 pub use self::spi::SpiInterface;
+pub use self::uart::UartInterface;
+
+/// Stands in for a delay source that is not needed: it returns at once
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+#[cfg(feature = "async")]
+impl embedded_hal_async::delay::DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// Stands in for an optional control line that is not connected.
+/// It never signals, and setting it does nothing.
+/// Its error type `E` can be chosen to match that of the other pins.
+#[derive(Debug, Clone, Copy)]
+pub struct NoPin<E = Infallible> {
+    _error: PhantomData<E>,
+}
+
+impl<E> NoPin<E> {
+    pub const fn new() -> Self {
+        Self {
+            _error: PhantomData,
+        }
+    }
+}
+
+impl<E> Default for NoPin<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: digital::Error> ErrorType for NoPin<E> {
+    type Error = E;
+}
+
+impl<E: digital::Error> InputPin for NoPin<E> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+impl<E: digital::Error> OutputPin for NoPin<E> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Waiting for the line to go low, or for any edge, never completes
+#[cfg(feature = "async")]
+impl<E: digital::Error> embedded_hal_async::digital::Wait for NoPin<E> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+}
 
 pub(crate) const PACKET_HEADER_LENGTH: usize = 4;
 pub(crate) const MAX_CARGO_DATA_LENGTH: usize = 32766 - PACKET_HEADER_LENGTH;
+/// Set in the second byte of the header of every segment of a packet
+/// but the first
+const CONTINUATION_FLAG: u8 = 0x80;
 
 struct SensorCommon {}
 
 impl SensorCommon {
+    /// Reject a read that starts with nothing but 0xFF bytes where
+    /// a packet header should be: the sensor hub did not drive the bus
+    fn check_garbage<CommE, PinE>(
+        read_buf: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        let header_len = read_buf.len().min(PACKET_HEADER_LENGTH);
+        if header_len > 0 && read_buf[..header_len].iter().all(|&b| b == 0xFF) {
+            return Err(Error::GarbageHeader);
+        }
+        Ok(())
+    }
+
+    /// Does this header start a segment continuing an earlier one?
+    fn is_continuation(header: &[u8]) -> bool {
+        header.len() >= PACKET_HEADER_LENGTH
+            && header[1] & CONTINUATION_FLAG != 0
+    }
+
+    /// Check that the first read of a packet whose header was read alone
+    /// starts the packet over: the sensor hub sends it again
+    /// from its original header, though it may set the continuation flag
+    /// on the re-sent header
+    fn check_restart_header<CommE, PinE>(
+        packet_header: &[u8],
+        segment_header: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        if segment_header[2] != packet_header[2] {
+            return Err(Error::SegmentChannelMismatch);
+        }
+        if segment_header[3] != packet_header[3] {
+            return Err(Error::SegmentSequenceMismatch);
+        }
+        if Self::parse_packet_header(segment_header)
+            != Self::parse_packet_header(packet_header)
+        {
+            return Err(Error::SegmentNotContinued);
+        }
+        Ok(())
+    }
+
+    /// Check that a segment continues the packet with the given header,
+    /// whose previous segment had sequence number `last_seq`.
+    /// Returns the sequence number of this segment.
+    fn check_segment_header<CommE, PinE>(
+        packet_header: &[u8],
+        last_seq: u8,
+        segment_header: &[u8],
+    ) -> Result<u8, Error<CommE, PinE>> {
+        if !Self::is_continuation(segment_header) {
+            return Err(Error::SegmentNotContinued);
+        }
+        if segment_header[2] != packet_header[2] {
+            return Err(Error::SegmentChannelMismatch);
+        }
+        // every transfer on a channel takes the next sequence number
+        let seq = segment_header[3];
+        if seq != last_seq.wrapping_add(1) {
+            return Err(Error::SegmentSequenceMismatch);
+        }
+        if Self::parse_packet_header(segment_header) <= PACKET_HEADER_LENGTH {
+            return Err(Error::PacketCutShort);
+        }
+        Ok(seq)
+    }
+
     fn parse_packet_header(packet: &[u8]) -> usize {
         const CONTINUATION_FLAG_MASK: u16 = 0x80;
         const CONTINUATION_FLAG_CLEAR: u16 = !(CONTINUATION_FLAG_MASK);
@@ -103,6 +337,7 @@ mod tests {
     use core::ops::Shr;
 
     #[test]
+    #[allow(clippy::assign_op_pattern, clippy::unnecessary_cast)]
     fn test_parse_packet_header() {
         let short_packet: [u8; 2] = [13, 15];
         let size = SensorCommon::parse_packet_header(&short_packet);
@@ -147,4 +382,70 @@ mod tests {
         let size = SensorCommon::parse_packet_header(&raw_packet);
         assert_eq!(size, 275, "verify > 255 packet length");
     }
+
+    #[test]
+    fn test_check_segment_header() {
+        let packet_header = [40, 0, 2, 7];
+        let check = |segment_header: [u8; PACKET_HEADER_LENGTH]| {
+            SensorCommon::check_segment_header::<(), ()>(
+                &packet_header,
+                7,
+                &segment_header,
+            )
+        };
+
+        assert_eq!(check([36, 0x80, 2, 8]).ok(), Some(8));
+        assert!(matches!(
+            check([36, 0, 2, 8]),
+            Err(Error::SegmentNotContinued)
+        ));
+        assert!(matches!(
+            check([36, 0x80, 3, 8]),
+            Err(Error::SegmentChannelMismatch)
+        ));
+        assert!(matches!(
+            check([36, 0x80, 2, 9]),
+            Err(Error::SegmentSequenceMismatch)
+        ));
+        assert!(matches!(check([4, 0x80, 2, 8]), Err(Error::PacketCutShort)));
+    }
+
+    #[test]
+    fn test_check_restart_header() {
+        let packet_header = [40, 0, 2, 7];
+        let check = |segment_header: [u8; PACKET_HEADER_LENGTH]| {
+            SensorCommon::check_restart_header::<(), ()>(
+                &packet_header,
+                &segment_header,
+            )
+        };
+
+        assert!(check([40, 0, 2, 7]).is_ok());
+        // the packet again, with the continuation flag set
+        assert!(check([40, 0x80, 2, 7]).is_ok());
+        // a continuation of the packet, rather than the packet again
+        assert!(matches!(
+            check([36, 0x80, 2, 7]),
+            Err(Error::SegmentNotContinued)
+        ));
+        assert!(matches!(
+            check([40, 0, 3, 7]),
+            Err(Error::SegmentChannelMismatch)
+        ));
+        assert!(matches!(
+            check([40, 0, 2, 8]),
+            Err(Error::SegmentSequenceMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_check_restart_header_captured() {
+        // the advertisement as re-sent by a sensor hub,
+        // after its header was read alone
+        let rc = SensorCommon::check_restart_header::<(), ()>(
+            &[0x14, 0x01, 0x00, 0x01],
+            &[0x14, 0x81, 0x00, 0x01],
+        );
+        assert!(rc.is_ok());
+    }
 }
@@ -0,0 +1,204 @@
+use crate::Error;
+use embedded_io::{Read, ReadReady};
+
+#[cfg(feature = "defmt-03")]
+use defmt_03 as defmt;
+
+/// Every UART-RVC frame starts with two of these bytes
+const RVC_HEADER_BYTE: u8 = 0xAA;
+/// Length of a UART-RVC frame, including header and checksum
+const RVC_FRAME_LEN: usize = 19;
+/// Angles are sent in hundredths of a degree
+const RVC_DEGREES_PER_LSB: f32 = 0.01;
+/// Standard gravity: accelerations are sent in milli-g
+const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// One heading sample sent by the sensor hub in UART-RVC mode
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RvcSample {
+    /// Frame counter, incremented for every frame sent
+    pub index: u8,
+    /// Yaw (degrees)
+    pub yaw: f32,
+    /// Pitch (degrees)
+    pub pitch: f32,
+    /// Roll (degrees)
+    pub roll: f32,
+    /// Linear acceleration including gravity, X Y Z (m/s^2)
+    pub accel: [f32; 3],
+}
+
+impl RvcSample {
+    /// Decode a whole frame whose checksum has been verified
+    fn from_frame(frame: &[u8; RVC_FRAME_LEN]) -> Self {
+        let read_i16 =
+            |idx: usize| i16::from_le_bytes([frame[idx], frame[idx + 1]]);
+        let degrees = |idx: usize| read_i16(idx) as f32 * RVC_DEGREES_PER_LSB;
+        let accel =
+            |idx: usize| read_i16(idx) as f32 * STANDARD_GRAVITY / 1000.0;
+
+        Self {
+            index: frame[2],
+            yaw: degrees(3),
+            pitch: degrees(5),
+            roll: degrees(7),
+            accel: [accel(9), accel(11), accel(13)],
+        }
+    }
+}
+
+/// Receives the heading frames streamed by the sensor hub in UART-RVC mode
+/// (robot vacuum cleaner mode), where the hub needs no commands at all.
+pub struct RvcReceiver<SER> {
+    /// serial port receiving from the sensor hub
+    serial: SER,
+    /// bytes of the frame received so far
+    frame: [u8; RVC_FRAME_LEN],
+    /// number of valid bytes in `frame`
+    frame_len: usize,
+    /// frames discarded because of a bad checksum
+    checksum_errors: u32,
+}
+
+impl<SER> RvcReceiver<SER> {
+    pub fn new(serial: SER) -> Self {
+        Self {
+            serial,
+            frame: [0; RVC_FRAME_LEN],
+            frame_len: 0,
+            checksum_errors: 0,
+        }
+    }
+
+    /// Returns the serial port
+    pub fn free(self) -> SER {
+        self.serial
+    }
+
+    /// Number of frames discarded because their checksum was wrong
+    pub fn checksum_errors(&self) -> u32 {
+        self.checksum_errors
+    }
+
+    /// Add one received byte, returning a sample when it completes
+    /// a valid frame. Useful when bytes arrive from an interrupt handler.
+    pub fn push_byte(&mut self, byte: u8) -> Option<RvcSample> {
+        // wait for the two header bytes before collecting the frame
+        if self.frame_len < 2 && byte != RVC_HEADER_BYTE {
+            self.frame_len = 0;
+            return None;
+        }
+        self.frame[self.frame_len] = byte;
+        self.frame_len += 1;
+        if self.frame_len < RVC_FRAME_LEN {
+            return None;
+        }
+
+        // the checksum is the sum of every byte after the header
+        let checksum = self.frame[2..RVC_FRAME_LEN - 1]
+            .iter()
+            .fold(0u8, |sum, &val| sum.wrapping_add(val));
+        if checksum == self.frame[RVC_FRAME_LEN - 1] {
+            self.frame_len = 0;
+            return Some(RvcSample::from_frame(&self.frame));
+        }
+
+        self.checksum_errors = self.checksum_errors.saturating_add(1);
+        self.resync();
+        None
+    }
+
+    /// Drop the start of a bad frame, keeping anything that might be
+    /// the header of the next one
+    fn resync(&mut self) {
+        let next_start = (1..RVC_FRAME_LEN)
+            .find(|&idx| {
+                self.frame[idx] == RVC_HEADER_BYTE
+                    && (idx + 1 == RVC_FRAME_LEN
+                        || self.frame[idx + 1] == RVC_HEADER_BYTE)
+            })
+            .unwrap_or(RVC_FRAME_LEN);
+        self.frame.copy_within(next_start.., 0);
+        self.frame_len = RVC_FRAME_LEN - next_start;
+    }
+}
+
+impl<SER, CommE> RvcReceiver<SER>
+where
+    SER: Read<Error = CommE> + ReadReady,
+{
+    /// Read every byte already received, returning the most recent
+    /// valid sample, if any. Does not block waiting for data.
+    pub fn read_sample(
+        &mut self,
+    ) -> Result<Option<RvcSample>, Error<CommE, ()>> {
+        let mut latest = None;
+        let mut byte = [0u8; 1];
+        while self.serial.read_ready().map_err(Error::Comm)? {
+            if self.serial.read(&mut byte).map_err(Error::Comm)? == 0 {
+                break;
+            }
+            if let Some(sample) = self.push_byte(byte[0]) {
+                latest = Some(sample);
+            }
+        }
+        Ok(latest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RvcReceiver, RvcSample};
+
+    /// A frame with a valid checksum
+    const RVC_FRAME: [u8; 19] = [
+        0xAA, 0xAA, // header
+        0xDE, // index
+        0x01, 0x00, 0x92, 0xFF, 0x25, 0x08, // yaw, pitch, roll
+        0x8D, 0xFE, 0xEC, 0xFF, 0xD1, 0x03, // accel X Y Z
+        0x00, 0x00, 0x00, // reserved
+        0xE7, // checksum
+    ];
+
+    fn push_all(
+        receiver: &mut RvcReceiver<()>,
+        bytes: &[u8],
+    ) -> Option<RvcSample> {
+        let mut latest = None;
+        for &byte in bytes {
+            if let Some(sample) = receiver.push_byte(byte) {
+                latest = Some(sample);
+            }
+        }
+        latest
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let mut receiver = RvcReceiver::new(());
+        let sample = push_all(&mut receiver, &RVC_FRAME).unwrap();
+        assert_eq!(sample.index, 0xDE);
+        assert!((sample.yaw - 0.01).abs() < 1e-4);
+        assert!((sample.pitch - -1.10).abs() < 1e-4);
+        assert!((sample.roll - 20.85).abs() < 1e-4);
+        assert!((sample.accel[0] - -3.6383).abs() < 1e-3);
+        assert!((sample.accel[1] - -0.1961).abs() < 1e-3);
+        assert!((sample.accel[2] - 9.5811).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_resync_after_garbage() {
+        let mut receiver = RvcReceiver::new(());
+        assert!(push_all(&mut receiver, &[0x12, 0xAA, 0x34]).is_none());
+
+        // a corrupted frame directly followed by a good one
+        let mut bad_frame = RVC_FRAME;
+        bad_frame[4] ^= 0x01;
+        assert!(push_all(&mut receiver, &bad_frame).is_none());
+        assert_eq!(receiver.checksum_errors(), 1);
+
+        let sample = push_all(&mut receiver, &RVC_FRAME);
+        assert_eq!(sample.map(|s| s.index), Some(0xDE));
+    }
+}
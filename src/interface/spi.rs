@@ -1,14 +1,74 @@
-use super::SensorInterface;
+use super::{NoDelay, NoPin, SensorInterface};
 use crate::interface::{SensorCommon, PACKET_HEADER_LENGTH};
 use embedded_hal::delay::DelayNs;
 use embedded_hal::digital::{InputPin, OutputPin};
 
+#[cfg(feature = "defmt-03")]
 use crate::defmt;
-
 #[cfg(feature = "defmt-03")]
 use crate::defmt::println;
 use crate::Error;
 use crate::Error::SensorUnresponsive;
+use crate::LogFormat;
+
+/// Size of the scratch buffer used to skip the tail of oversized packets
+const DISCARD_CHUNK_LEN: usize = 32;
+/// Longest wait for the sensor hub to answer WAKE on HINTN,
+/// in microseconds
+pub(super) const WAKE_TIMEOUT_US: u32 = 10_000;
+/// Interval between checks of HINTN while waiting for it to answer WAKE,
+/// in microseconds
+const WAKE_POLL_US: u32 = 10;
+/// Capacity of the queue of packets waiting to be sent, in bytes
+const OUTBOUND_QUEUE_LEN: usize = 64;
+
+/// Packets waiting to be clocked out while the next incoming packet
+/// is read. Each SHTP packet starts with its own length, so they are
+/// simply stored back to back.
+struct OutboundQueue {
+    buf: [u8; OUTBOUND_QUEUE_LEN],
+    len: usize,
+}
+
+impl OutboundQueue {
+    const fn new() -> Self {
+        Self {
+            buf: [0; OUTBOUND_QUEUE_LEN],
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append a packet, returning false if it does not fit
+    /// or its header does not match its length
+    fn push(&mut self, packet: &[u8]) -> bool {
+        let packet_len = packet.len();
+        if packet_len <= PACKET_HEADER_LENGTH
+            || SensorCommon::parse_packet_header(packet) != packet_len
+            || self.len + packet_len > OUTBOUND_QUEUE_LEN
+        {
+            return false;
+        }
+        self.buf[self.len..self.len + packet_len].copy_from_slice(packet);
+        self.len += packet_len;
+        true
+    }
+
+    /// Move the oldest packet into `dest`, returning its length
+    fn take(&mut self, dest: &mut [u8; OUTBOUND_QUEUE_LEN]) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let packet_len = SensorCommon::parse_packet_header(&self.buf);
+        dest[..packet_len].copy_from_slice(&self.buf[..packet_len]);
+        self.buf.copy_within(packet_len..self.len, 0);
+        self.len -= packet_len;
+        packet_len
+    }
+}
 
 /// Encapsulates all the lines required to operate this sensor
 /// - SCK: clock line from master
@@ -25,16 +85,29 @@ pub struct SpiControlLines<SPI, CSN, IN, RSTN> {
 }
 
 /// This combines the SPI peripheral and associated control pins
+/// - WAK: optional PS0/WAKE line, added with `with_wake`.
+///   Without it, PS0/WAKE must be tied high.
+/// - D: delay source used to time the sensor hub's answer to WAKE
 ///
-pub struct SpiInterface<SPI, CSN, IN, RSTN> {
+/// CSN is held low across the several transfers of each packet,
+/// so the SPI device must not drive a chip select of its own.
+/// To share an SPI bus between sensor hubs, give each `SpiInterface`
+/// a shared device whose chip select is a `NoPin`, such as a
+/// `RefCellDevice` from `embedded-hal-bus`, along with its own CSN.
+pub struct SpiInterface<SPI, CSN, IN, RSTN, WAK = NoPin, D = NoDelay> {
     spi: SPI,
     csn: CSN,
     hintn: IN,
     reset: RSTN,
-    received_packet_count: usize,
+    wake: Option<WAK>,
+    /// delay source used to time the sensor hub's answer to WAKE
+    wake_delay: D,
+    /// packets to send along with the next packet read
+    outbound: OutboundQueue,
 }
 
-impl<SPI, CSN, IN, RSTN, CommE, PinE> SpiInterface<SPI, CSN, IN, RSTN>
+impl<SPI, CSN, IN, RSTN, CommE, PinE>
+    SpiInterface<SPI, CSN, IN, RSTN, NoPin<PinE>>
 where
     // SPI: embedded_hal::blocking::spi::Write<u8, Error = CommE>
     //     + embedded_hal::blocking::spi::Transfer<u8, Error = CommE>,
@@ -51,7 +124,42 @@ where
             csn: lines.csn,
             hintn: lines.hintn,
             reset: lines.reset,
-            received_packet_count: 0,
+            wake: None,
+            wake_delay: NoDelay,
+            outbound: OutboundQueue::new(),
+        }
+    }
+}
+
+impl<SPI, CSN, IN, RSTN, WAK, D, CommE, PinE>
+    SpiInterface<SPI, CSN, IN, RSTN, WAK, D>
+where
+    // SPI: embedded_hal::blocking::spi::Write<u8, Error = CommE>
+    //     + embedded_hal::blocking::spi::Transfer<u8, Error = CommE>,
+    SPI: embedded_hal::spi::SpiDevice<Error = CommE>,
+    CSN: OutputPin<Error = PinE>,
+    IN: InputPin<Error = PinE>,
+    RSTN: OutputPin<Error = PinE>,
+    WAK: OutputPin<Error = PinE>,
+    D: DelayNs,
+    CommE: core::fmt::Debug,
+    PinE: core::fmt::Debug,
+{
+    /// Drive the sensor hub's PS0/WAKE line, to wake it before
+    /// writing to it. `delay` times the wait for its answer on HINTN.
+    pub fn with_wake<P: OutputPin<Error = PinE>, DW: DelayNs>(
+        self,
+        wake: P,
+        delay: DW,
+    ) -> SpiInterface<SPI, CSN, IN, RSTN, P, DW> {
+        SpiInterface {
+            spi: self.spi,
+            csn: self.csn,
+            hintn: self.hintn,
+            reset: self.reset,
+            wake: Some(wake),
+            wake_delay: delay,
+            outbound: self.outbound,
         }
     }
 
@@ -95,42 +203,177 @@ where
         false
     }
 
+    /// Queue a packet to be sent while reading the packet the sensor hub
+    /// is signalling. Return false if there is no such packet
+    /// or the packet does not fit in the queue.
+    fn queue_outbound(&mut self, packet: &[u8]) -> bool {
+        self.hintn_signaled() && self.outbound.push(packet)
+    }
+
+    /// Write a packet in a transaction of its own, first waking the
+    /// sensor hub if we have a WAK pin: it may be asleep, and would
+    /// then miss the packet
+    fn write_unqueued(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        if self.wake.is_none() {
+            return self.write_selected(packet);
+        }
+
+        let rc = self.wake_and_write(packet);
+        // release WAK whatever happened, reporting the first error
+        let released = match self.wake.as_mut() {
+            Some(wake) => wake.set_high().map_err(Error::Pin),
+            None => Ok(()),
+        };
+        rc.and(released)
+    }
+
+    /// Pull WAK low, then write a packet once the sensor hub answers
+    fn wake_and_write(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        if let Some(wake) = self.wake.as_mut() {
+            wake.set_low().map_err(Error::Pin)?;
+        }
+        if !self.wait_for_wake_answer()? {
+            #[cfg(feature = "defmt-03")]
+            println!("no answer to WAKE");
+            return Err(SensorUnresponsive);
+        }
+        self.write_selected(packet)
+    }
+
+    /// Wait up to `WAKE_TIMEOUT_US` for the sensor hub to answer WAK low
+    /// by signalling on HINTN.
+    /// Return true if it did, false if it did not answer in time.
+    fn wait_for_wake_answer(&mut self) -> Result<bool, Error<CommE, PinE>> {
+        for _ in 0..WAKE_TIMEOUT_US / WAKE_POLL_US {
+            if self.hintn.is_low().map_err(Error::Pin)? {
+                return Ok(true);
+            }
+            self.wake_delay.delay_us(WAKE_POLL_US);
+        }
+        Ok(false)
+    }
+
+    /// Write a packet in a transaction of its own
+    fn write_selected(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<(), Error<CommE, PinE>> {
+        self.csn.set_low().map_err(Error::Pin)?;
+        let rc = self.spi.write(packet).map_err(Error::Comm);
+        self.csn.set_high().map_err(Error::Pin)?;
+        rc
+    }
+
+    /// Read a complete packet, clocking out `send_packet` at the same time
+    fn exchange_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+        send_packet: &[u8],
+    ) -> Result<usize, Error<CommE, PinE>> {
+        //Zero the header bytes, in case nothing is received
+        for i in recv_buf[..PACKET_HEADER_LENGTH].iter_mut() {
+            *i = 0;
+        }
+
+        // grab this sensor
+        self.csn.set_low().map_err(Error::Pin)?;
+        // get just the header
+        let rc = self
+            .spi
+            .transfer(
+                &mut recv_buf[..PACKET_HEADER_LENGTH],
+                sub_slice(send_packet, 0, PACKET_HEADER_LENGTH),
+            )
+            .map_err(Error::Comm);
+
+        if let Err(e) = rc {
+            //release the sensor
+            self.csn.set_high().map_err(Error::Pin)?;
+            return Err(e);
+        }
+
+        let rc = self.read_packet_cargo(recv_buf, send_packet);
+
+        //release the sensor
+        self.csn.set_high().map_err(Error::Pin)?;
+        let packet_len = rc.map_err(Error::Comm)?;
+
+        Ok(packet_len)
+    }
+
     /// read the body ("cargo" or "payload") of a packet,
-    /// return the total packet length read
-    fn read_packet_cargo(&mut self, recv_buf: &mut [u8]) -> usize {
-        let mut packet_len = SensorCommon::parse_packet_header(
+    /// while clocking out the body of `send_packet`.
+    /// return the total packet length, even when the packet
+    /// did not fit in `recv_buf`
+    fn read_packet_cargo(
+        &mut self,
+        recv_buf: &mut [u8],
+        send_packet: &[u8],
+    ) -> Result<usize, CommE> {
+        let packet_len = SensorCommon::parse_packet_header(
             &recv_buf[..PACKET_HEADER_LENGTH],
         );
+        let recv_body_len = packet_len.saturating_sub(PACKET_HEADER_LENGTH);
+        let send_body =
+            sub_slice(send_packet, PACKET_HEADER_LENGTH, usize::MAX);
+        // the transfer lasts as long as the longer of the two packets
+        let transfer_len = recv_body_len.max(send_body.len());
+
+        // a packet too large for the receive buffer is drained,
+        // keeping only its leading part
+        let kept_len = packet_len.min(recv_buf.len());
+        let kept_body_len = kept_len.saturating_sub(PACKET_HEADER_LENGTH);
+
         // now get the body
-        if (packet_len > PACKET_HEADER_LENGTH) && (packet_len < recv_buf.len())
-        {
-            //exchange 0xFF bytes for whatever the sensor is sending
-            for w in recv_buf[PACKET_HEADER_LENGTH..packet_len].iter_mut() {
-                *w = 0xFF;
-            }
-            let rc = self
-                .spi
-                .transfer(&mut recv_buf[PACKET_HEADER_LENGTH..packet_len], &[]);
-            if rc.is_err() {
-                packet_len = 0;
-            }
-        } else {
-            packet_len = 0;
+        if kept_body_len > 0 {
+            self.spi.transfer(
+                &mut recv_buf[PACKET_HEADER_LENGTH..kept_len],
+                sub_slice(send_body, 0, kept_body_len),
+            )?;
         }
 
-        packet_len
+        let mut discard_buf = [0u8; DISCARD_CHUNK_LEN];
+        let mut done_len = kept_body_len;
+        while done_len < transfer_len {
+            let chunk_len = (transfer_len - done_len).min(DISCARD_CHUNK_LEN);
+            self.spi.transfer(
+                &mut discard_buf[..chunk_len],
+                sub_slice(send_body, done_len, chunk_len),
+            )?;
+            done_len += chunk_len;
+        }
+
+        if packet_len <= PACKET_HEADER_LENGTH {
+            return Ok(0);
+        }
+        Ok(packet_len)
     }
 }
 
-impl<SPI, CSN, IN, RS, CommE, PinE> SensorInterface
-    for SpiInterface<SPI, CSN, IN, RS>
+/// The part of `bytes` starting at `start`, at most `len` long
+fn sub_slice(bytes: &[u8], start: usize, len: usize) -> &[u8] {
+    let start = start.min(bytes.len());
+    let end = start.saturating_add(len).min(bytes.len());
+    &bytes[start..end]
+}
+
+impl<SPI, CSN, IN, RS, WAK, D, CommE, PinE> SensorInterface
+    for SpiInterface<SPI, CSN, IN, RS, WAK, D>
 where
     SPI: embedded_hal::spi::SpiDevice<Error = CommE>,
     CSN: OutputPin<Error = PinE>,
     IN: InputPin<Error = PinE>,
     RS: OutputPin<Error = PinE>,
-    CommE: core::fmt::Debug + defmt::Format,
-    PinE: core::fmt::Debug + defmt::Format,
+    WAK: OutputPin<Error = PinE>,
+    D: DelayNs,
+    CommE: core::fmt::Debug + LogFormat,
+    PinE: core::fmt::Debug + LogFormat,
 {
     type SensorError = Error<CommE, PinE>;
 
@@ -144,8 +387,11 @@ where
     ) -> Result<(), Self::SensorError> {
         // Deselect sensor
         self.csn.set_high().map_err(Error::Pin)?;
-        // Note: This assumes that WAK/PS0 is set to high already
-        //TODO allow the user to provide a WAK pin
+        // WAK/PS0 must be high when the sensor hub leaves reset,
+        // to select SPI mode: without a WAK pin, it must be tied high
+        if let Some(wake) = self.wake.as_mut() {
+            wake.set_high().map_err(Error::Pin)?;
+        }
         // should already be high by default, but just in case...
         self.reset.set_high().map_err(Error::Pin)?;
 
@@ -173,115 +419,430 @@ where
         send_buf: &[u8],
         recv_buf: &mut [u8],
     ) -> Result<usize, Self::SensorError> {
-        // select the sensor
-        self.csn.set_low().map_err(Error::Pin)?;
-        let rc = self.spi.write(&send_buf).map_err(Error::Comm);
-        self.csn.set_high().map_err(Error::Pin)?;
-        if rc.is_err() {
-            //release the sensor
-            return Err(rc.unwrap_err());
+        // when a packet is waiting to be read, ours is sent along with it
+        if !self.queue_outbound(send_buf) {
+            // keep packets in order
+            self.flush_outbound()?;
+            self.write_unqueued(send_buf)?;
+            #[cfg(feature = "defmt-03")]
+            println!("sent {}", send_buf.len());
+
+            if !self.block_on_hintn(1000) {
+                //no packet to be read
+                #[cfg(feature = "defmt-03")]
+                println!("no packet to read?");
+                recv_buf[..PACKET_HEADER_LENGTH].fill(0);
+                return Ok(0);
+            }
         }
-        #[cfg(feature = "defmt-03")]
-        println!("sent {}", send_buf.len());
 
-        //zero the receive buffer
-        for i in recv_buf[..PACKET_HEADER_LENGTH].iter_mut() {
-            *i = 0;
+        self.read_packet(recv_buf)
+    }
+
+    /// Queue the packet if the sensor hub has a packet waiting to be read,
+    /// so that both go through in the same transaction.
+    /// Otherwise, write it right away.
+    /// A queued packet is only sent by the next `read_packet`,
+    /// `read_with_timeout` or `flush_outbound`, which report any error
+    /// sending it.
+    fn write_packet(&mut self, packet: &[u8]) -> Result<(), Self::SensorError> {
+        if self.queue_outbound(packet) {
+            return Ok(());
         }
+        // keep packets in order
+        self.flush_outbound()?;
+        self.write_unqueued(packet)
+    }
 
-        if !self.block_on_hintn(1000) {
-            //no packet to be read
-            #[cfg(feature = "defmt-03")]
-            println!("no packet to read?");
-            return Ok(0);
+    /// Read a complete packet from the sensor,
+    /// sending the oldest queued packet at the same time.
+    /// The queued packet is dropped even if the exchange fails,
+    /// as the sensor hub may have received part of it.
+    fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        // Note: HINTN cannot always be used to detect data ready.
+        // As soon as host selects CSN, HINTN resets
+        let mut send_buf = [0u8; OUTBOUND_QUEUE_LEN];
+        let send_len = self.outbound.take(&mut send_buf);
+        self.exchange_packet(recv_buf, &send_buf[..send_len])
+    }
+
+    /// Send every queued packet on its own.
+    /// A packet that fails to go through is dropped, not retried.
+    fn flush_outbound(&mut self) -> Result<(), Self::SensorError> {
+        let mut send_buf = [0u8; OUTBOUND_QUEUE_LEN];
+        loop {
+            let send_len = self.outbound.take(&mut send_buf);
+            if send_len == 0 {
+                return Ok(());
+            }
+            self.write_unqueued(&send_buf[..send_len])?;
         }
+    }
 
-        self.csn.set_low().map_err(Error::Pin)?;
-        // get just the header
-        let rc = self
-            .spi
-            .transfer(&mut recv_buf[..PACKET_HEADER_LENGTH], &[])
-            .map_err(Error::Comm);
-        if rc.is_err() {
-            //release the sensor
-            #[cfg(feature = "defmt-03")]
-            println!("transfer err: {:?}", rc);
-            self.csn.set_high().map_err(Error::Pin)?;
-            return Err(rc.unwrap_err());
+    fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        if self.wait_for_sensor_awake(delay_source, max_ms) {
+            return self.read_packet(recv_buf);
         }
+        // nothing to read: queued packets cannot wait any longer
+        self.flush_outbound()?;
+        Ok(0)
+    }
+}
 
-        let packet_len = self.read_packet_cargo(recv_buf);
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{SpiControlLines, SpiInterface, WAKE_TIMEOUT_US};
+    use crate::interface::mock_interface::{FakeDelay, FakePin};
+    use crate::interface::{NoDelay, NoPin, SensorInterface};
+    use crate::wrapper::BNO080;
+    use crate::Error;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+    use embedded_hal::delay::DelayNs;
+    use embedded_hal::digital::{self, InputPin, OutputPin};
+    use embedded_hal::spi::{self, Operation};
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// An SPI device that records every byte written to it,
+    /// and replays queued bytes when read.
+    /// Once `transactions_left` runs out, every transaction fails.
+    #[derive(Default)]
+    struct FakeSpi {
+        available_bytes: VecDeque<u8>,
+        written: Vec<u8>,
+        transactions_left: Option<usize>,
+    }
 
-        //release the sensor
-        self.csn.set_high().map_err(Error::Pin)?;
+    impl spi::ErrorType for FakeSpi {
+        type Error = spi::ErrorKind;
+    }
 
-        if packet_len > 0 {
-            self.received_packet_count += 1;
+    impl spi::SpiDevice for FakeSpi {
+        fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Self::Error> {
+            if let Some(left) = self.transactions_left.as_mut() {
+                if *left == 0 {
+                    return Err(spi::ErrorKind::Other);
+                }
+                *left -= 1;
+            }
+            for op in operations.iter_mut() {
+                match op {
+                    Operation::Read(read) => self.read_into(read),
+                    Operation::Write(bytes) => {
+                        self.written.extend_from_slice(bytes)
+                    }
+                    Operation::Transfer(read, write) => {
+                        self.written.extend_from_slice(write);
+                        self.read_into(read);
+                    }
+                    Operation::TransferInPlace(bytes) => {
+                        self.written.extend_from_slice(bytes);
+                        self.read_into(bytes);
+                    }
+                    Operation::DelayNs(_) => {}
+                }
+            }
+            Ok(())
         }
+    }
 
-        Ok(packet_len)
+    impl FakeSpi {
+        /// Fill `read` with the next bytes available, then zeroes
+        fn read_into(&mut self, read: &mut [u8]) {
+            for byte in read.iter_mut() {
+                *byte = self.available_bytes.pop_front().unwrap_or(0);
+            }
+        }
     }
 
-    fn write_packet(&mut self, packet: &[u8]) -> Result<(), Self::SensorError> {
-        self.csn.set_low().map_err(Error::Pin)?;
-        let rc = self.spi.write(&packet).map_err(Error::Comm);
-        self.csn.set_high().map_err(Error::Pin)?;
+    /// HINTN, driven by the sensor hub
+    struct FakeHintn {
+        low: Rc<Cell<bool>>,
+    }
+
+    impl digital::ErrorType for FakeHintn {
+        type Error = Infallible;
+    }
 
-        if rc.is_err() {
-            return Err(rc.unwrap_err());
+    impl InputPin for FakeHintn {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.low.get())
         }
 
-        Ok(())
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.low.get())
+        }
     }
 
-    /// Read a complete packet from the sensor
-    fn read_packet(
-        &mut self,
-        recv_buf: &mut [u8],
-    ) -> Result<usize, Self::SensorError> {
-        // Note: HINTN cannot always be used to detect data ready.
-        // As soon as host selects CSN, HINTN resets
+    /// WAK, which the sensor hub answers on HINTN when `responsive`
+    struct FakeWake {
+        hintn_low: Rc<Cell<bool>>,
+        responsive: bool,
+        driven: Vec<bool>,
+    }
 
-        //Zero the header bytes are zeroed since we're not sending any data
-        for i in recv_buf[..PACKET_HEADER_LENGTH].iter_mut() {
-            *i = 0;
+    impl digital::ErrorType for FakeWake {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for FakeWake {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.driven.push(false);
+            self.hintn_low.set(self.responsive);
+            Ok(())
         }
 
-        // grab this sensor
-        self.csn.set_low().map_err(Error::Pin)?;
-        // get just the header
-        let rc = self
-            .spi
-            .transfer(&mut recv_buf[..PACKET_HEADER_LENGTH], &[])
-            .map_err(Error::Comm);
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.driven.push(true);
+            Ok(())
+        }
+    }
 
-        if rc.is_err() {
-            //release the sensor
-            self.csn.set_high().map_err(Error::Pin)?;
-            return Err(rc.unwrap_err());
+    /// A delay source that adds up the time it was asked to wait
+    #[derive(Default)]
+    struct TimedDelay {
+        waited_ns: u64,
+    }
+
+    impl DelayNs for TimedDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.waited_ns += u64::from(ns);
         }
+    }
 
-        let packet_len = self.read_packet_cargo(recv_buf);
+    type FakeSpiInterface<WAK, D = NoDelay> =
+        SpiInterface<FakeSpi, FakePin, FakeHintn, FakePin, WAK, D>;
+
+    fn make_interface(
+        hintn_low: &Rc<Cell<bool>>,
+    ) -> FakeSpiInterface<NoPin<Infallible>> {
+        SpiInterface::new(SpiControlLines {
+            spi: FakeSpi::default(),
+            csn: FakePin::default(),
+            hintn: FakeHintn {
+                low: hintn_low.clone(),
+            },
+            reset: FakePin::default(),
+        })
+    }
 
-        //release the sensor
-        self.csn.set_high().map_err(Error::Pin)?;
+    fn interface_with_wake(
+        responsive: bool,
+    ) -> FakeSpiInterface<FakeWake, TimedDelay> {
+        let hintn_low = Rc::new(Cell::new(false));
+        let wake = FakeWake {
+            hintn_low: hintn_low.clone(),
+            responsive,
+            driven: Vec::new(),
+        };
+        make_interface(&hintn_low).with_wake(wake, TimedDelay::default())
+    }
 
-        if packet_len > 0 {
-            self.received_packet_count += 1;
-        }
+    const SEND_PACKET: [u8; 6] = [6, 0, 2, 0, 0xF9, 0];
 
-        Ok(packet_len)
+    #[test]
+    fn test_write_wakes_sensor() {
+        let mut interface = interface_with_wake(true);
+        interface.write_packet(&SEND_PACKET).unwrap();
+
+        assert_eq!(interface.spi.written, SEND_PACKET);
+        // WAK is asserted then released around the transfer
+        let wake = interface.wake.as_ref().unwrap();
+        assert_eq!(wake.driven, [false, true]);
+        assert_eq!(interface.csn.driven, [false, true]);
+        // the sensor hub answered at once
+        assert_eq!(interface.wake_delay.waited_ns, 0);
     }
 
-    fn read_with_timeout(
-        &mut self,
-        recv_buf: &mut [u8],
-        delay_source: &mut impl DelayNs,
-        max_ms: u8,
-    ) -> Result<usize, Self::SensorError> {
-        if self.wait_for_sensor_awake(delay_source, max_ms) {
-            return self.read_packet(recv_buf);
+    #[test]
+    fn test_write_to_sleeping_sensor() {
+        let mut interface = interface_with_wake(false);
+        let rc = interface.write_packet(&SEND_PACKET);
+
+        assert!(matches!(rc, Err(Error::SensorUnresponsive)));
+        assert!(interface.spi.written.is_empty());
+        assert!(interface.csn.driven.is_empty());
+        let wake = interface.wake.as_ref().unwrap();
+        assert_eq!(wake.driven, [false, true]);
+        // the answer is awaited for a set time, not a number of polls
+        let waited_us = interface.wake_delay.waited_ns / 1000;
+        assert_eq!(waited_us, u64::from(WAKE_TIMEOUT_US));
+    }
+
+    #[test]
+    fn test_write_while_reading() {
+        let hintn_low = Rc::new(Cell::new(true));
+        let mut interface = make_interface(&hintn_low);
+        let incoming = [9, 0, 3, 1, 0xFB, 1, 2, 3, 4];
+        interface.spi.available_bytes.extend(incoming);
+
+        // the sensor hub has a packet waiting, so ours is held back...
+        interface.write_packet(&SEND_PACKET).unwrap();
+        assert!(interface.spi.written.is_empty());
+
+        // ...and sent while reading it, in a single transaction
+        let mut recv_buf = [0u8; 32];
+        let recv_len = interface.read_packet(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], incoming);
+        assert_eq!(interface.spi.written, SEND_PACKET);
+        assert_eq!(interface.csn.driven, [false, true]);
+
+        // nothing left to send
+        interface.spi.available_bytes.extend(incoming);
+        interface.read_packet(&mut recv_buf).unwrap();
+        assert_eq!(interface.spi.written, SEND_PACKET);
+    }
+
+    #[test]
+    fn test_exchange_fails_partway() {
+        let hintn_low = Rc::new(Cell::new(true));
+        let mut interface = make_interface(&hintn_low);
+        interface
+            .spi
+            .available_bytes
+            .extend([9, 0, 3, 1, 0xFB, 1, 2, 3, 4]);
+        interface.write_packet(&SEND_PACKET).unwrap();
+
+        // the header goes through, then the bus fails
+        interface.spi.transactions_left = Some(1);
+        let mut recv_buf = [0u8; 32];
+        let rc = interface.read_packet(&mut recv_buf);
+        assert!(matches!(rc, Err(Error::Comm(spi::ErrorKind::Other))));
+        assert_eq!(interface.spi.written, SEND_PACKET[..4]);
+        assert_eq!(interface.csn.driven, [false, true]);
+
+        // the packet cut short is not sent again
+        interface.spi.transactions_left = None;
+        hintn_low.set(false);
+        assert!(interface.flush_outbound().is_ok());
+        assert_eq!(interface.spi.written, SEND_PACKET[..4]);
+    }
+
+    #[test]
+    fn test_unanswered_command_not_held_back() {
+        let hintn_low = Rc::new(Cell::new(true));
+        let mut shub = BNO080::new_with_interface(make_interface(&hintn_low));
+
+        // the sensor hub has a packet waiting, but WAKE is sent at once:
+        // no answer will come to carry it along
+        assert!(shub.wake().is_ok());
+        let interface = shub.free();
+        assert_eq!(interface.spi.written, [5, 0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_queued_write_not_stranded() {
+        let hintn_low = Rc::new(Cell::new(true));
+        let mut interface = make_interface(&hintn_low);
+        interface.write_packet(&SEND_PACKET).unwrap();
+        assert!(interface.spi.written.is_empty());
+
+        // the packet waiting was read elsewhere: ours is sent on its own
+        hintn_low.set(false);
+        let mut recv_buf = [0u8; 32];
+        let recv_len = interface
+            .read_with_timeout(&mut recv_buf, &mut FakeDelay {}, 2)
+            .unwrap();
+        assert_eq!(recv_len, 0);
+        assert_eq!(interface.spi.written, SEND_PACKET);
+    }
+
+    /// Sensor hubs sharing an SPI bus through `embedded-hal-bus`,
+    /// whose errors are only loggable with its own defmt feature
+    #[cfg(not(feature = "defmt-03"))]
+    mod shared_bus {
+        use super::*;
+        use core::cell::RefCell;
+        use embedded_hal_bus::spi::RefCellDevice;
+
+        /// An SPI bus that records every byte written to it
+        #[derive(Default)]
+        struct FakeSpiBus {
+            written: Vec<u8>,
+        }
+
+        impl spi::ErrorType for FakeSpiBus {
+            type Error = Infallible;
+        }
+
+        impl spi::SpiBus for FakeSpiBus {
+            fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+                words.fill(0);
+                Ok(())
+            }
+
+            fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+                self.written.extend_from_slice(words);
+                Ok(())
+            }
+
+            fn transfer(
+                &mut self,
+                read: &mut [u8],
+                write: &[u8],
+            ) -> Result<(), Self::Error> {
+                self.written.extend_from_slice(write);
+                read.fill(0);
+                Ok(())
+            }
+
+            fn transfer_in_place(
+                &mut self,
+                words: &mut [u8],
+            ) -> Result<(), Self::Error> {
+                self.written.extend_from_slice(words);
+                words.fill(0);
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), Self::Error> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn test_shared_bus() {
+            let bus = RefCell::new(FakeSpiBus::default());
+            let hintn_low = Rc::new(Cell::new(false));
+            let mut hubs: [_; 2] = core::array::from_fn(|_| {
+                // each sensor hub is selected through its own CSN
+                let device = RefCellDevice::new_no_delay(
+                    &bus,
+                    NoPin::<Infallible>::new(),
+                );
+                SpiInterface::new(SpiControlLines {
+                    spi: device.unwrap(),
+                    csn: FakePin::default(),
+                    hintn: FakeHintn {
+                        low: hintn_low.clone(),
+                    },
+                    reset: FakePin::default(),
+                })
+            });
+
+            hubs[1].write_packet(&SEND_PACKET).unwrap();
+            hubs[0].write_packet(&[5, 0, 1, 0, 2]).unwrap();
+
+            assert_eq!(bus.borrow().written[..6], SEND_PACKET);
+            assert_eq!(bus.borrow().written[6..], [5, 0, 1, 0, 2]);
+            for hub in &hubs {
+                assert_eq!(hub.csn.driven, [false, true]);
+            }
         }
-        Ok(0)
     }
 }
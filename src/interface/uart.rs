@@ -0,0 +1,454 @@
+use super::{SensorInterface, PACKET_HEADER_LENGTH};
+use crate::Error;
+use embedded_hal::delay::DelayNs;
+use embedded_io::{Read, ReadReady, Write};
+
+#[cfg(feature = "defmt-03")]
+use defmt_03 as defmt;
+
+#[cfg(feature = "defmt-03")]
+use defmt::println;
+
+/// Flag byte that opens and closes every UART frame
+const FRAME_FLAG: u8 = 0x7E;
+/// Control escape byte, followed by an escaped byte
+const CONTROL_ESCAPE: u8 = 0x7D;
+/// Escaped bytes are transmitted XOR'ed with this value
+const ESCAPE_XOR: u8 = 0x20;
+
+/// Protocol ID of buffer status query (BSQ) and notification (BSN) frames
+const PROTOCOL_BUFFER_STATUS: u8 = 0;
+/// Protocol ID of frames carrying an SHTP packet
+const PROTOCOL_SHTP: u8 = 1;
+
+/// The sensor hub needs this long between bytes it receives
+const INTER_BYTE_DELAY_US: u32 = 100;
+/// How long to wait for a buffer status notification
+const BSN_TIMEOUT_US: u32 = 10_000;
+/// How long the hub may pause partway through a frame
+/// before the frame is considered cut off
+const FRAME_BYTE_TIMEOUT_US: u32 = 2_000;
+/// How often to query the hub's buffer status before giving up
+const MAX_BUFFER_STATUS_QUERIES: u8 = 5;
+/// Length of the buffer holding a packet received during a write
+const PENDING_BUF_LEN: usize = 256;
+
+/// Communicates with the sensor hub using UART-SHTP:
+/// each SHTP packet is carried in a byte-stuffed frame,
+/// and the host asks the hub how much room it has before each write.
+///
+/// The serial port must implement `ReadReady` so that reads can time out.
+/// The delay source paces transmitted bytes as the hub requires.
+pub struct UartInterface<SER, D> {
+    /// serial port connected to the sensor hub
+    serial: SER,
+    /// delay source used to pace transmitted bytes
+    delay: D,
+    /// a packet received while waiting for a buffer status notification
+    pending_buf: [u8; PENDING_BUF_LEN],
+    /// length of the packet received during a write, if any,
+    /// which may exceed the length of `pending_buf`
+    pending_len: usize,
+}
+
+impl<SER, D, CommE> UartInterface<SER, D>
+where
+    SER: Read<Error = CommE> + ReadReady + Write,
+    D: DelayNs,
+{
+    pub fn new(serial: SER, delay: D) -> Self {
+        Self {
+            serial,
+            delay,
+            pending_buf: [0; PENDING_BUF_LEN],
+            pending_len: 0,
+        }
+    }
+
+    /// Returns the serial port and delay source
+    pub fn free(self) -> (SER, D) {
+        (self.serial, self.delay)
+    }
+
+    /// Block until one byte has been read
+    fn read_byte(&mut self) -> Result<u8, Error<CommE, ()>> {
+        let mut byte = [0u8; 1];
+        let read_len = self.serial.read(&mut byte).map_err(Error::Comm)?;
+        if read_len == 0 {
+            // the serial port has reached end of file
+            return Err(Error::SensorUnresponsive);
+        }
+        Ok(byte[0])
+    }
+
+    /// Read the next byte of a frame that has started arriving,
+    /// giving up if the hub stops sending partway through
+    fn read_frame_byte(&mut self) -> Result<u8, Error<CommE, ()>> {
+        let mut waited_us = 0;
+        while !self.serial.read_ready().map_err(Error::Comm)? {
+            if waited_us >= FRAME_BYTE_TIMEOUT_US {
+                return Err(Error::PacketCutShort);
+            }
+            self.delay.delay_us(INTER_BYTE_DELAY_US);
+            waited_us += INTER_BYTE_DELAY_US;
+        }
+        self.read_byte()
+    }
+
+    /// Read one frame, if one has started arriving.
+    /// Returns the frame's protocol ID and the number of bytes decoded,
+    /// which may exceed the length of `buf`: bytes beyond its end
+    /// are dropped.
+    fn read_frame(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<Option<(u8, usize)>, Error<CommE, ()>> {
+        // skip anything preceding the opening flag
+        loop {
+            if !self.serial.read_ready().map_err(Error::Comm)? {
+                return Ok(None);
+            }
+            if self.read_byte()? == FRAME_FLAG {
+                break;
+            }
+        }
+
+        let mut protocol_id = None;
+        let mut decoded_len = 0;
+        let mut escaped = false;
+        loop {
+            let mut byte = self.read_frame_byte()?;
+            if byte == FRAME_FLAG {
+                match protocol_id {
+                    // back-to-back flags: this one opens the frame
+                    None => continue,
+                    Some(id) => return Ok(Some((id, decoded_len))),
+                }
+            }
+            if escaped {
+                byte ^= ESCAPE_XOR;
+                escaped = false;
+            } else if byte == CONTROL_ESCAPE {
+                escaped = true;
+                continue;
+            }
+
+            if protocol_id.is_none() {
+                protocol_id = Some(byte);
+            } else {
+                if decoded_len < buf.len() {
+                    buf[decoded_len] = byte;
+                }
+                decoded_len += 1;
+            }
+        }
+    }
+
+    /// Send one byte, then give the hub time to receive it
+    fn write_paced_byte(&mut self, byte: u8) -> Result<(), Error<CommE, ()>> {
+        self.serial.write_all(&[byte]).map_err(Error::Comm)?;
+        self.serial.flush().map_err(Error::Comm)?;
+        self.delay.delay_us(INTER_BYTE_DELAY_US);
+        Ok(())
+    }
+
+    /// Send a frame with the given protocol ID, escaping its contents
+    fn write_frame(
+        &mut self,
+        protocol_id: u8,
+        data: &[u8],
+    ) -> Result<(), Error<CommE, ()>> {
+        self.write_paced_byte(FRAME_FLAG)?;
+        for &byte in [protocol_id].iter().chain(data) {
+            if byte == FRAME_FLAG || byte == CONTROL_ESCAPE {
+                self.write_paced_byte(CONTROL_ESCAPE)?;
+                self.write_paced_byte(byte ^ ESCAPE_XOR)?;
+            } else {
+                self.write_paced_byte(byte)?;
+            }
+        }
+        self.write_paced_byte(FRAME_FLAG)
+    }
+
+    /// Ask the hub how many bytes its receive buffer can take.
+    /// An SHTP packet received meanwhile is kept for the next read.
+    fn query_buffer_status(&mut self) -> Result<usize, Error<CommE, ()>> {
+        self.write_frame(PROTOCOL_BUFFER_STATUS, &[])?;
+
+        let mut waited_us = 0;
+        while waited_us < BSN_TIMEOUT_US {
+            let mut frame_buf = [0u8; PENDING_BUF_LEN];
+            match self.read_frame(&mut frame_buf)? {
+                Some((PROTOCOL_BUFFER_STATUS, len)) if len >= 2 => {
+                    let free_len =
+                        u16::from_le_bytes([frame_buf[0], frame_buf[1]]);
+                    return Ok(free_len as usize);
+                }
+                Some((PROTOCOL_SHTP, len)) => {
+                    if self.pending_len == 0 {
+                        let kept_len = len.min(PENDING_BUF_LEN);
+                        self.pending_buf[..kept_len]
+                            .copy_from_slice(&frame_buf[..kept_len]);
+                        self.pending_len = len;
+                    } else {
+                        #[cfg(feature = "defmt-03")]
+                        println!("uart drop {}", len);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    self.delay.delay_us(INTER_BYTE_DELAY_US);
+                    waited_us += INTER_BYTE_DELAY_US;
+                }
+            }
+        }
+
+        Err(Error::SensorUnresponsive)
+    }
+}
+
+impl<SER, D, CommE> SensorInterface for UartInterface<SER, D>
+where
+    SER: Read<Error = CommE> + ReadReady + Write,
+    D: DelayNs,
+{
+    type SensorError = Error<CommE, ()>;
+
+    fn requires_soft_reset(&self) -> bool {
+        true
+    }
+
+    fn setup(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), Self::SensorError> {
+        self.pending_len = 0;
+        delay_source.delay_ms(5);
+        Ok(())
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> Result<(), Self::SensorError> {
+        // wait until the hub has room for the whole packet
+        let mut queries = 0;
+        while self.query_buffer_status()? < packet.len() {
+            queries += 1;
+            if queries >= MAX_BUFFER_STATUS_QUERIES {
+                return Err(Error::SensorUnresponsive);
+            }
+        }
+        self.write_frame(PROTOCOL_SHTP, packet)
+    }
+
+    /// Returns the length of the packet received, which may exceed the
+    /// length of `recv_buf`: bytes beyond its end are dropped.
+    /// A packet received during a write that did not fit in the buffer
+    /// holding it meanwhile gives `Error::FrameTooLong`.
+    fn read_packet(
+        &mut self,
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        if self.pending_len > 0 {
+            let len = self.pending_len;
+            self.pending_len = 0;
+            if len > PENDING_BUF_LEN {
+                return Err(Error::FrameTooLong(len));
+            }
+            let kept_len = len.min(recv_buf.len());
+            recv_buf[..kept_len].copy_from_slice(&self.pending_buf[..kept_len]);
+            return Ok(len);
+        }
+
+        // skip buffer status notifications nobody asked for
+        while let Some((protocol_id, len)) = self.read_frame(recv_buf)? {
+            if protocol_id == PROTOCOL_SHTP && len >= PACKET_HEADER_LENGTH {
+                return Ok(len);
+            }
+        }
+        Ok(0)
+    }
+
+    fn read_with_timeout(
+        &mut self,
+        recv_buf: &mut [u8],
+        delay_source: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, Self::SensorError> {
+        let mut total_delay: u8 = 0;
+        loop {
+            let read_size = self.read_packet(recv_buf)?;
+            if read_size > 0 || total_delay >= max_ms {
+                return Ok(read_size);
+            }
+            // no data available yet...wait a while longer
+            delay_source.delay_ms(1);
+            total_delay += 1;
+        }
+    }
+
+    fn send_and_receive_packet(
+        &mut self,
+        send_buf: &[u8],
+        recv_buf: &mut [u8],
+    ) -> Result<usize, Self::SensorError> {
+        self.write_packet(send_buf)?;
+        self.read_packet(recv_buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{UartInterface, PENDING_BUF_LEN};
+    use crate::interface::mock_interface::FakeDelay;
+    use crate::interface::SensorInterface;
+    use crate::Error;
+    use embedded_io::{ErrorKind, ErrorType, Read, ReadReady, Write};
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// A serial port that replays canned bytes and records written bytes
+    #[derive(Default)]
+    struct FakeSerial {
+        rx_bytes: VecDeque<u8>,
+        tx_bytes: Vec<u8>,
+    }
+
+    impl ErrorType for FakeSerial {
+        type Error = ErrorKind;
+    }
+
+    impl Read for FakeSerial {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let mut len = 0;
+            while len < buf.len() {
+                match self.rx_bytes.pop_front() {
+                    Some(byte) => buf[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            Ok(len)
+        }
+    }
+
+    impl ReadReady for FakeSerial {
+        fn read_ready(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.rx_bytes.is_empty())
+        }
+    }
+
+    impl Write for FakeSerial {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx_bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut serial = FakeSerial::default();
+        // buffer status notification: 512 bytes free
+        serial.rx_bytes.extend([0x7E, 0x00, 0x00, 0x02, 0x7E]);
+
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        assert!(uart.write_packet(&[6, 0, 2, 0, 0x7E, 0x7D]).is_ok());
+
+        let (serial, _) = uart.free();
+        assert_eq!(
+            serial.tx_bytes,
+            [
+                0x7E, 0x00, 0x7E, // buffer status query
+                0x7E, 0x01, // SHTP frame
+                6, 0, 2, 0, 0x7D, 0x5E, 0x7D, 0x5D, // escaped packet
+                0x7E,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_packet_no_room() {
+        let mut serial = FakeSerial::default();
+        for _ in 0..5 {
+            serial.rx_bytes.extend([0x7E, 0x00, 0x02, 0x00, 0x7E]);
+        }
+
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        assert!(uart.write_packet(&[5, 0, 1, 0, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let mut serial = FakeSerial::default();
+        serial.rx_bytes.extend([
+            0x7E, 0x00, 0x00, 0x01, 0x7E, // unsolicited buffer status
+            0x7E, 0x7E, 0x01, // back-to-back flags, SHTP frame
+            6, 0, 2, 0, 0x7D, 0x5E, 0x7D, 0x5D, // escaped packet
+            0x7E,
+        ]);
+
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        let mut recv_buf = [0u8; 16];
+        let len = uart.read_packet(&mut recv_buf).unwrap();
+        assert_eq!(recv_buf[..len], [6, 0, 2, 0, 0x7E, 0x7D]);
+        assert_eq!(uart.read_packet(&mut recv_buf).ok(), Some(0));
+    }
+
+    #[test]
+    fn test_packet_received_during_write() {
+        let mut serial = FakeSerial::default();
+        serial.rx_bytes.extend([
+            0x7E, 0x01, 5, 0, 1, 0, 0x01, 0x7E, // reset complete
+            0x7E, 0x00, 0x00, 0x01, 0x7E, // buffer status notification
+        ]);
+
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        assert!(uart.write_packet(&[5, 0, 1, 0, 0x01]).is_ok());
+        let mut recv_buf = [0u8; 16];
+        let len = uart.read_packet(&mut recv_buf).unwrap();
+        assert_eq!(recv_buf[..len], [5, 0, 1, 0, 0x01]);
+    }
+
+    #[test]
+    fn test_frame_cut_short() {
+        let mut serial = FakeSerial::default();
+        serial.rx_bytes.extend([0x7E, 0x01, 6, 0, 2]);
+
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        let mut recv_buf = [0u8; 16];
+        let rc = uart.read_packet(&mut recv_buf);
+        assert!(matches!(rc, Err(Error::PacketCutShort)));
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut serial = FakeSerial::default();
+        serial.rx_bytes.extend([0x7E, 0x01, 20, 0, 2, 0]);
+        serial.rx_bytes.extend([0; 16]);
+        serial.rx_bytes.push_back(0x7E);
+
+        // the whole length is reported, keeping what fits
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        let mut recv_buf = [0u8; 8];
+        assert_eq!(uart.read_packet(&mut recv_buf).ok(), Some(20));
+        assert_eq!(recv_buf[..4], [20, 0, 2, 0]);
+
+        // a packet too long to be held during a write is dropped
+        let (mut serial, _) = uart.free();
+        let oversized_len = PENDING_BUF_LEN + 4;
+        serial.rx_bytes.extend([0x7E, 0x01]);
+        serial.rx_bytes.extend([0; PENDING_BUF_LEN + 4]);
+        serial.rx_bytes.push_back(0x7E);
+        serial.rx_bytes.extend([0x7E, 0x00, 0x00, 0x01, 0x7E]);
+        let mut uart = UartInterface::new(serial, FakeDelay {});
+        assert!(uart.write_packet(&[5, 0, 1, 0, 0x01]).is_ok());
+        let rc = uart.read_packet(&mut recv_buf);
+        assert!(
+            matches!(rc, Err(Error::FrameTooLong(len)) if len == oversized_len)
+        );
+    }
+}
@@ -17,6 +17,20 @@ use defmt_03 as defmt;
 #[cfg(not(feature = "defmt-03"))]
 use dummy_defmt as defmt;
 
+/// Error types the driver can log: with the `defmt-03` feature,
+/// these must implement `defmt::Format`; without it, any type will do
+#[cfg(feature = "defmt-03")]
+pub trait LogFormat: defmt::Format {}
+#[cfg(feature = "defmt-03")]
+impl<T: defmt::Format + ?Sized> LogFormat for T {}
+
+/// Error types the driver can log: with the `defmt-03` feature,
+/// these must implement `defmt::Format`; without it, any type will do
+#[cfg(not(feature = "defmt-03"))]
+pub trait LogFormat {}
+#[cfg(not(feature = "defmt-03"))]
+impl<T: ?Sized> LogFormat for T {}
+
 /// Errors in this crate
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
@@ -27,4 +41,22 @@ pub enum Error<CommE, PinE> {
     Pin(PinE),
     /// The sensor is not responding
     SensorUnresponsive,
+    /// A packet segment did not continue the packet being read:
+    /// the sensor hub moved on to another packet
+    SegmentNotContinued,
+    /// A packet segment was on another channel than the packet being read
+    SegmentChannelMismatch,
+    /// A packet segment had an unexpected sequence number
+    SegmentSequenceMismatch,
+    /// The sensor hub ended a packet before sending all of it
+    PacketCutShort,
+    /// The rest of a packet that could not be read earlier was received,
+    /// and discarded
+    OrphanSegment,
+    /// Only 0xFF bytes were read where a packet header was expected:
+    /// the sensor hub did not answer
+    GarbageHeader,
+    /// A UART frame was too long for the buffer receiving it,
+    /// and was dropped
+    FrameTooLong(usize),
 }
@@ -5,12 +5,22 @@ LICENSE: BSD3 (see LICENSE file)
 
 use crate::defmt;
 use crate::interface::{SensorInterface, PACKET_HEADER_LENGTH};
+use crate::LogFormat;
 use core::ops::Shr;
 use defmt::println;
 use embedded_hal::delay::DelayNs;
 
-const PACKET_SEND_BUF_LEN: usize = 256;
-const PACKET_RECV_BUF_LEN: usize = 1024;
+#[cfg(feature = "async")]
+mod asynch;
+#[cfg(feature = "async")]
+pub use self::asynch::BNO080Async;
+
+/// Default length of the buffer for packets sent to the sensor hub
+pub const PACKET_SEND_BUF_LEN: usize = 256;
+/// Default length of the buffer for packets received from the sensor hub
+pub const PACKET_RECV_BUF_LEN: usize = 1024;
+/// Input report IDs below this have their sequence numbers tracked
+const NUM_TRACKED_REPORT_IDS: usize = 0x30;
 
 const NUM_CHANNELS: usize = 6;
 
@@ -23,35 +33,477 @@ pub enum WrapperError<E> {
     InvalidChipId(u8),
     /// Unsupported sensor firmware version
     InvalidFWVersion(u8),
-    /// We expected some data but didn't receive any
+    /// We expected some data but didn't receive any before timing out
     NoDataAvailable,
+    /// The sensor hub is asleep: call `wake` before configuring reports
+    SensorAsleep,
+    /// The sensor hub did not answer the given command in time
+    CommandTimeout(u8),
+    /// The sensor hub reported a non-zero status for an initialize command
+    InitializeFailed(u8),
+    /// A packet was shorter than its header
+    MalformedPacket,
+    /// A packet of the given length did not fit in our receive buffer,
+    /// and was dropped
+    OversizedPacket(usize),
+    /// A packet ended before the end of the report with the given ID
+    TruncatedReport(u8),
+    /// A packet arrived on a channel this driver does not know
+    UnknownChannel(u8),
+    /// A packet of the given length does not fit in our send buffer
+    PacketTooLarge(usize),
+}
+
+/// A single input report received from the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorSample {
+    /// Report ID of the sensor that produced this sample
+    pub report_id: u8,
+    /// Per-report sequence number
+    pub sequence: u8,
+    /// Accuracy status (0: unreliable .. 3: high)
+    pub status: u8,
+    /// When the sample was taken, in microseconds relative to the moment
+    /// the sensor hub signalled the packet carrying it.
+    /// Batched samples have negative timestamps.
+    pub timestamp_us: i32,
+    /// Raw Q-point fixed point values, in the order sent by the sensor hub
+    pub data: [i16; 5],
+}
+
+/// Reason reported by the sensor hub for its most recent reset
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResetCause {
+    /// No reset cause reported
+    #[default]
+    NotApplicable,
+    /// Power on reset
+    PowerOn,
+    /// Internal system reset
+    InternalReset,
+    /// Watchdog timeout
+    Watchdog,
+    /// External reset
+    External,
+    /// Any other reset cause
+    Other,
+}
+
+impl From<u8> for ResetCause {
+    fn from(val: u8) -> Self {
+        match val {
+            0 => ResetCause::NotApplicable,
+            1 => ResetCause::PowerOn,
+            2 => ResetCause::InternalReset,
+            3 => ResetCause::Watchdog,
+            4 => ResetCause::External,
+            _ => ResetCause::Other,
+        }
+    }
+}
+
+/// Product ID record describing one firmware component of the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProductIdRecord {
+    /// Cause of the most recent reset (only set in the first record)
+    pub reset_cause: ResetCause,
+    /// Software version major
+    pub sw_version_major: u8,
+    /// Software version minor
+    pub sw_version_minor: u8,
+    /// Software part number
+    pub sw_part_number: u32,
+    /// Software build number
+    pub sw_build_number: u32,
+    /// Software version patch
+    pub sw_version_patch: u16,
+}
+
+/// Maximum number of product ID records we keep
+const MAX_PRODUCT_ID_RECORDS: usize = 5;
+
+/// Every product ID record reported by the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Default)]
+pub struct ProductInfo {
+    records: [ProductIdRecord; MAX_PRODUCT_ID_RECORDS],
+    record_count: usize,
+}
+
+impl ProductInfo {
+    /// The product ID records received, one per firmware component
+    pub fn records(&self) -> &[ProductIdRecord] {
+        &self.records[..self.record_count]
+    }
+
+    /// Cause of the most recent sensor hub reset
+    pub fn reset_cause(&self) -> ResetCause {
+        self.records()
+            .first()
+            .map(|record| record.reset_cause)
+            .unwrap_or_default()
+    }
+
+    fn push(&mut self, record: ProductIdRecord) {
+        if self.record_count < MAX_PRODUCT_ID_RECORDS {
+            self.records[self.record_count] = record;
+            self.record_count += 1;
+        }
+    }
+}
+
+/// Something notable that happened while handling messages
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The sensor hub reset without being asked to,
+    /// disabling every report that had been enabled.
+    /// The cause is `NotApplicable` if the event is taken before
+    /// the product ID reporting it arrives.
+    Reset(ResetCause),
+    /// The sensor hub sent an error list we did not ask for,
+    /// starting with the given error; the whole list is available
+    /// from `error_list`. Not raised while another event is pending.
+    HubError(ShtpError),
+}
+
+/// Error reported by the sensor hub's SHTP layer in its error list
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShtpError {
+    /// No error
+    NoError,
+    /// Hub application attempted to exceed the maximum read cargo length
+    ReadCargoTooLong,
+    /// Host write was too short to hold a packet header
+    WriteTooShort,
+    /// Host wrote a header with a length greater than the maximum
+    /// write cargo length
+    WriteCargoTooLong,
+    /// Host wrote a header with a length less than or equal to
+    /// the header length
+    WriteCargoTooShort,
+    /// Host transfer was shorter than the cargo length in its header
+    FragmentedWrite,
+    /// Host wrote a continuation when no fragment was in progress
+    UnexpectedContinuation,
+    /// Unrecognized command on the command channel
+    UnknownCommand,
+    /// Unrecognized parameter to the get advertisement command
+    UnknownAdvertisementParameter,
+    /// Host wrote to an unrecognized channel
+    UnknownChannel,
+    /// Advertisement requested while an advertisement response was pending
+    AdvertisementPending,
+    /// Host wrote before the hub finished sending its advertisement
+    WriteBeforeAdvertisement,
+    /// Error list too long to send, truncated
+    ErrorListTruncated,
+    /// Any other error code
+    Other(u8),
+}
+
+impl From<u8> for ShtpError {
+    fn from(val: u8) -> Self {
+        match val {
+            0 => ShtpError::NoError,
+            1 => ShtpError::ReadCargoTooLong,
+            2 => ShtpError::WriteTooShort,
+            3 => ShtpError::WriteCargoTooLong,
+            4 => ShtpError::WriteCargoTooShort,
+            5 => ShtpError::FragmentedWrite,
+            6 => ShtpError::UnexpectedContinuation,
+            7 => ShtpError::UnknownCommand,
+            8 => ShtpError::UnknownAdvertisementParameter,
+            9 => ShtpError::UnknownChannel,
+            10 => ShtpError::AdvertisementPending,
+            11 => ShtpError::WriteBeforeAdvertisement,
+            12 => ShtpError::ErrorListTruncated,
+            _ => ShtpError::Other(val),
+        }
+    }
+}
+
+/// Maximum number of errors kept from the sensor hub's error list
+const MAX_ERROR_LIST_LEN: usize = 16;
+
+/// Per-sensor statistics kept by the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SensorCounts {
+    /// Samples produced by the sensor
+    pub offered: u32,
+    /// Samples accepted for delivery to the host
+    pub accepted: u32,
+    /// Samples produced while the report was enabled
+    pub on: u32,
+    /// Samples the hub attempted to deliver to the host
+    pub attempted: u32,
+}
+
+impl SensorCounts {
+    /// Gather the counts from the responses to a get counts command
+    fn from_responses(responses: &[CommandResponse]) -> Self {
+        // R0 subcommand, R1 sensor ID, R2 reserved,
+        // then two counts per response in R3..R6 and R7..R10
+        let mut counts = Self::default();
+        for resp in responses {
+            if resp.response_sequence == 0 {
+                counts.offered = resp.value_u32(3);
+                counts.accepted = resp.value_u32(7);
+            } else {
+                counts.on = resp.value_u32(3);
+                counts.attempted = resp.value_u32(7);
+            }
+        }
+        counts
+    }
+}
+
+/// Clock source of the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OscillatorType {
+    /// Internal oscillator
+    Internal,
+    /// External crystal
+    ExternalCrystal,
+    /// External clock signal
+    ExternalClock,
+    /// Any other oscillator type
+    Other(u8),
+}
+
+impl From<u8> for OscillatorType {
+    fn from(val: u8) -> Self {
+        match val {
+            0 => OscillatorType::Internal,
+            1 => OscillatorType::ExternalCrystal,
+            2 => OscillatorType::ExternalClock,
+            _ => OscillatorType::Other(val),
+        }
+    }
+}
+
+/// Part of the sensor hub to restart with `reinitialize`
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitSubsystem {
+    /// The entire system
+    EntireSystem,
+    /// Only the sensor hub
+    SensorHub,
+}
+
+impl From<InitSubsystem> for u8 {
+    fn from(val: InitSubsystem) -> Self {
+        match val {
+            InitSubsystem::EntireSystem => SH2_INIT_SYSTEM,
+            InitSubsystem::SensorHub => SH2_INIT_SENSOR_HUB,
+        }
+    }
+}
+
+/// Power state of the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowerState {
+    /// Sensors are running and reports are delivered as configured
+    #[default]
+    On,
+    /// Sensors are stopped to save power, except those configured
+    /// to be always on
+    Sleep,
+}
+
+/// Counters describing the health of the link with the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// Packets received on each channel
+    pub packets_received: [u32; NUM_CHANNELS],
+    /// Total bytes received, including packet headers
+    pub bytes_received: u32,
+    /// Reports this driver does not know how to handle
+    pub unknown_reports: u32,
+    /// Packets that were truncated, malformed or on an unknown channel
+    pub malformed_packets: u32,
+    /// Packets dropped because they did not fit in our receive buffer
+    pub oversized_packets: u32,
+    /// Errors reported by the sensor interface
+    pub comm_errors: u32,
+    /// Waits for a response that ended without one
+    pub timeouts: u32,
+    /// Packets missing from the sequence received on each channel
+    pub dropped_packets: [u32; NUM_CHANNELS],
+    /// Input reports missing from the sequence received, for all reports
+    pub dropped_reports: u32,
+    /// Resets announced by the sensor hub
+    pub resets: u32,
+    /// Features enabled while the feature table was full: these are not
+    /// reapplied after a reset
+    pub unremembered_features: u32,
+    /// Error lists holding errors that the sensor hub sent
+    /// without being asked
+    pub hub_errors: u32,
+}
+
+/// Maximum number of enabled features remembered for reapplying after a reset
+pub const MAX_ENABLED_FEATURES: usize = 8;
+
+/// Complete configuration sent to the sensor hub to enable a feature (report)
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeatureConfig {
+    /// Report ID of the feature
+    pub report_id: u8,
+    /// Feature flags (change sensitivity, wake-up and always-on control)
+    pub flags: u8,
+    /// Change sensitivity, in the units of the report
+    pub change_sensitivity: u16,
+    /// Report interval in microseconds; zero disables the feature
+    pub micros_between_reports: u32,
+    /// Batch interval in microseconds
+    pub batch_interval_us: u32,
+    /// Sensor-specific configuration word
+    pub sensor_specific_config: u32,
+}
+
+/// Maximum number of responses collected for a single command request
+const MAX_COMMAND_RESPONSES: usize = 2;
+
+/// A command response record received from the sensor hub
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct CommandResponse {
+    /// Command the response belongs to
+    command: u8,
+    /// Sequence number of the command request being answered
+    command_sequence: u8,
+    /// Position of this response among several for the same request
+    response_sequence: u8,
+    /// Response values R0..R10
+    values: [u8; 11],
+}
+
+impl CommandResponse {
+    /// Little-endian u32 starting at response value `index`
+    fn value_u32(&self, index: usize) -> u32 {
+        u32::from_le_bytes([
+            self.values[index],
+            self.values[index + 1],
+            self.values[index + 2],
+            self.values[index + 3],
+        ])
+    }
+}
+
+/// Progress of a command request awaiting its responses
+struct CommandWait {
+    /// Command awaiting responses
+    command: u8,
+    /// Number of responses expected
+    response_count: usize,
+    /// Longest time to wait for them, in milliseconds
+    timeout_ms: u16,
+    /// Time spent waiting so far, in milliseconds
+    waited_ms: u16,
+}
+
+/// A request decided on while handling a received packet,
+/// to be sent once the packet is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeferredRequest {
+    /// Ask for the product ID, and with it the reset cause
+    ProductId,
+    /// Configure every enabled feature again
+    ReapplyFeatures,
 }
 
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 #[derive(Debug)]
-pub struct BNO080<SI> {
+pub struct BNO080<
+    SI,
+    const SEND_BUF_LEN: usize = PACKET_SEND_BUF_LEN,
+    const RECV_BUF_LEN: usize = PACKET_RECV_BUF_LEN,
+> {
     pub(crate) sensor_interface: SI,
     /// each communication channel with the device has its own sequence number
     sequence_numbers: [u8; NUM_CHANNELS],
+    /// sequence number of the last packet received on each channel
+    rx_sequence_numbers: [Option<u8>; NUM_CHANNELS],
+    /// sequence number of the last input report received, by report ID
+    report_sequence_numbers: [Option<u8>; NUM_TRACKED_REPORT_IDS],
+    /// input reports missing from the sequence received, by report ID
+    dropped_reports: [u32; NUM_TRACKED_REPORT_IDS],
+    /// link health counters
+    stats: Stats,
     /// buffer for building and sending packet to the sensor hub
-    packet_send_buf: [u8; PACKET_SEND_BUF_LEN],
+    packet_send_buf: [u8; SEND_BUF_LEN],
     /// buffer for building packets received from the sensor hub
-    packet_recv_buf: [u8; PACKET_RECV_BUF_LEN],
+    packet_recv_buf: [u8; RECV_BUF_LEN],
 
     last_packet_len_received: usize,
     /// has the device been succesfully reset
     device_reset: bool,
     /// has the product ID been verified
     prod_id_verified: bool,
+    /// product ID records received from the sensor hub
+    product_info: ProductInfo,
+    /// oldest firmware major version accepted, 0 to accept any
+    min_fw_version_major: u8,
 
     init_received: bool,
 
     /// have we received the full advertisement
     advert_received: bool,
 
+    /// has `init` completed
+    initialized: bool,
+    /// power state most recently requested of the sensor hub
+    power_state: PowerState,
+    /// are we waiting for the sensor hub to reset at our request
+    reset_expected: bool,
+    /// time spent polling without data since a reset became expected
+    reset_waited_ms: u16,
+    /// has the sensor hub completed a reset without yet announcing
+    /// that it is initialized
+    reset_in_progress: bool,
+    /// has an unexpected reset been noticed, pending its reset cause
+    awaiting_reset_cause: bool,
+    /// should enabled features be configured again after an unexpected reset
+    reapply_on_reset: bool,
+    /// configuration of every feature we have enabled
+    enabled_features: [Option<FeatureConfig>; MAX_ENABLED_FEATURES],
+    /// most recent event not yet taken by the application
+    pending_event: Option<Event>,
+    /// should the product ID be requested once the current packet is handled
+    product_id_request_pending: bool,
+    /// should enabled features be reapplied once the current packet is handled
+    reapply_pending: bool,
+
+    /// sequence number of the next command request
+    command_sequence: u8,
+    /// command and sequence number of the request awaiting responses
+    pending_command: Option<(u8, u8)>,
+    /// responses received for the pending command
+    command_responses: [CommandResponse; MAX_COMMAND_RESPONSES],
+    /// number of valid entries in `command_responses`
+    command_response_count: usize,
+
+    /// report ID of the most recent flush completed by the sensor hub
+    last_flush_completed: Option<u8>,
+
     /// have we received an error list
     error_list_received: bool,
-    last_error_received: u8,
+    /// are we waiting for an error list we asked for
+    error_list_requested: bool,
+    /// errors most recently reported by the sensor hub
+    error_list: [ShtpError; MAX_ERROR_LIST_LEN],
+    error_list_len: usize,
 
     last_chan_received: u8,
     last_exec_chan_rid: u8,
@@ -72,18 +524,63 @@ pub struct BNO080<SI> {
 
 impl<SI> BNO080<SI> {
     pub fn new_with_interface(sensor_interface: SI) -> Self {
+        Self::new_with_buffers(sensor_interface)
+    }
+}
+
+impl<SI, const SEND_BUF_LEN: usize, const RECV_BUF_LEN: usize>
+    BNO080<SI, SEND_BUF_LEN, RECV_BUF_LEN>
+{
+    /// Buffers must have room for more than a packet header
+    const BUF_LEN_CHECK: () = assert!(
+        SEND_BUF_LEN > PACKET_HEADER_LENGTH
+            && RECV_BUF_LEN > PACKET_HEADER_LENGTH,
+        "packet buffers too small"
+    );
+
+    /// Use packet buffers of the given sizes, rather than
+    /// `PACKET_SEND_BUF_LEN` and `PACKET_RECV_BUF_LEN`, for example:
+    /// `BNO080::<_, 64, 512>::new_with_buffers(sensor_interface)`.
+    /// Received packets that do not fit are dropped.
+    pub fn new_with_buffers(sensor_interface: SI) -> Self {
+        let () = Self::BUF_LEN_CHECK;
         Self {
             sensor_interface,
             sequence_numbers: [0; NUM_CHANNELS],
-            packet_send_buf: [0; PACKET_SEND_BUF_LEN],
-            packet_recv_buf: [0; PACKET_RECV_BUF_LEN],
+            rx_sequence_numbers: [None; NUM_CHANNELS],
+            report_sequence_numbers: [None; NUM_TRACKED_REPORT_IDS],
+            dropped_reports: [0; NUM_TRACKED_REPORT_IDS],
+            stats: Stats::default(),
+            packet_send_buf: [0; SEND_BUF_LEN],
+            packet_recv_buf: [0; RECV_BUF_LEN],
             last_packet_len_received: 0,
             device_reset: false,
             prod_id_verified: false,
+            product_info: ProductInfo::default(),
+            min_fw_version_major: 0,
             init_received: false,
             advert_received: false,
+            initialized: false,
+            power_state: PowerState::On,
+            reset_expected: false,
+            reset_waited_ms: 0,
+            reset_in_progress: false,
+            awaiting_reset_cause: false,
+            reapply_on_reset: false,
+            enabled_features: [None; MAX_ENABLED_FEATURES],
+            pending_event: None,
+            product_id_request_pending: false,
+            reapply_pending: false,
+            command_sequence: 0,
+            pending_command: None,
+            command_responses: [CommandResponse::default();
+                MAX_COMMAND_RESPONSES],
+            command_response_count: 0,
+            last_flush_completed: None,
             error_list_received: false,
-            last_error_received: 0,
+            error_list_requested: false,
+            error_list: [ShtpError::NoError; MAX_ERROR_LIST_LEN],
+            error_list_len: 0,
             last_chan_received: 0,
             last_exec_chan_rid: 0,
             last_command_chan_rid: 0,
@@ -95,38 +592,46 @@ impl<SI> BNO080<SI> {
     }
 
     /// Returns previously consumed serial sensor instance.
+    /// Any packets the sensor interface still holds back are not sent:
+    /// call `flush_outbound` first to send them.
     pub fn free(self) -> SI {
         self.sensor_interface
     }
 }
 
-impl<SI, SE> BNO080<SI>
+impl<SI, SE, const SEND_BUF_LEN: usize, const RECV_BUF_LEN: usize>
+    BNO080<SI, SEND_BUF_LEN, RECV_BUF_LEN>
 where
     SI: SensorInterface<SensorError = SE>,
-    SE: core::fmt::Debug + defmt::Format,
+    SE: core::fmt::Debug + LogFormat,
 {
     /// Consume all available messages on the port without processing them
-    pub fn eat_all_messages(&mut self, delay: &mut impl DelayNs) {
+    pub fn eat_all_messages(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
         println!("eat_n");
         loop {
-            let msg_count = self.eat_one_message(delay);
+            let msg_count = self.eat_one_message(delay)?;
             if msg_count == 0 {
                 break;
             }
             //give some time to other parts of the system
             delay.delay_ms(1);
         }
+        Ok(())
     }
 
-    /// Handle any messages with a timeout
+    /// Handle any messages with a timeout,
+    /// returning the number of messages handled
     pub fn handle_all_messages(
         &mut self,
         delay: &mut impl DelayNs,
         timeout_ms: u8,
-    ) -> u32 {
+    ) -> Result<u32, WrapperError<SE>> {
         let mut total_handled: u32 = 0;
         loop {
-            let handled_count = self.handle_one_message(delay, timeout_ms);
+            let handled_count = self.handle_one_message(delay, timeout_ms)?;
             if handled_count == 0 {
                 break;
             } else {
@@ -135,7 +640,7 @@ where
                 delay.delay_ms(1);
             }
         }
-        total_handled
+        Ok(total_handled)
     }
 
     /// return the number of messages handled
@@ -143,38 +648,597 @@ where
         &mut self,
         delay: &mut impl DelayNs,
         max_ms: u8,
-    ) -> u32 {
-        let mut msg_count = 0;
+    ) -> Result<u32, WrapperError<SE>> {
+        let received_len = self.receive_packet_with_timeout(delay, max_ms)?;
+        if received_len == 0 {
+            self.idle_polled(max_ms);
+            return Ok(0);
+        }
+        self.handle_received_packet(received_len)?;
+        Ok(1)
+    }
+
+    /// Receive and ignore one message,
+    /// returning the size of the packet received or zero
+    /// if there was no packet to read.
+    pub fn eat_one_message(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<usize, WrapperError<SE>> {
+        let received_len = self.receive_packet_with_timeout(delay, 150)?;
+        println!("e1 {}", received_len);
+        Ok(received_len)
+    }
+
+    /// Handle a packet of `received_len` bytes in the receive buffer
+    pub fn handle_received_packet(
+        &mut self,
+        received_len: usize,
+    ) -> Result<(), WrapperError<SE>> {
+        self.dispatch_received_packet(received_len, &mut |_| {})
+    }
+
+    /// Handle a received packet, passing any input reports it contains
+    /// to `sink`, then send any requests it called for
+    fn dispatch_received_packet(
+        &mut self,
+        received_len: usize,
+        sink: &mut impl FnMut(&SensorSample),
+    ) -> Result<(), WrapperError<SE>> {
+        let rc = self.process_received_packet(received_len, sink);
+        self.send_deferred_requests()?;
+        rc
+    }
+
+    /// Send the requests decided on while handling a received packet
+    fn send_deferred_requests(&mut self) -> Result<(), WrapperError<SE>> {
+        while let Some(request) = self.take_deferred_request() {
+            match request {
+                DeferredRequest::ProductId => {
+                    self.send_packet(CHANNEL_HUB_CONTROL, &PRODUCT_ID_REQUEST)?;
+                }
+                DeferredRequest::ReapplyFeatures => self.reapply_features()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The BNO080 starts up with all sensors disabled,
+    /// waiting for the application to configure it.
+    pub fn init(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        println!("wrapper init");
+        self.start_init();
+
+        //Section 5.1.1.1 : On system startup, the SHTP control application will send
+        // its full advertisement response, unsolicited, to the host.
+        delay_source.delay_ms(1);
+        let rc = self.sensor_interface.setup(delay_source);
+        self.count_comm_error(rc)?;
+
+        if self.sensor_interface.requires_soft_reset() {
+            delay_source.delay_ms(1);
+            self.soft_reset()?;
+            delay_source.delay_ms(150);
+            self.eat_all_messages(delay_source)?;
+            delay_source.delay_ms(50);
+            self.eat_all_messages(delay_source)?;
+        } else {
+            // we only expect two messages after reset:
+            // eat the advertisement response
+            self.eat_one_message(delay_source)?;
+            // eat the unsolicited initialization response
+            self.eat_one_message(delay_source)?;
+        }
+
+        self.verify_product_id(delay_source)?;
+        //self.eat_all_messages(delay_source);
+
+        self.finish_init();
+        Ok(())
+    }
+
+    /// Tell the sensor to start reporting the fused rotation vector
+    /// on a regular cadence. Note that the maximum valid update rate
+    /// is 1 kHz, based on the max update rate of the sensor's gyros.
+    pub fn enable_rotation_vector(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(
+            SENSOR_REPORTID_ROTATION_VECTOR,
+            flags,
+            millis_between_reports,
+        )
+    }
+
+    /// Enables reporting of linear acceleration vector.
+    pub fn enable_linear_accel(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(
+            SENSOR_REPORTID_LINEAR_ACCEL,
+            flags,
+            millis_between_reports,
+        )
+    }
+
+    /// Enables reporting of gyroscope data.
+    pub fn enable_gyro(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(SENSOR_REPORTID_GYRO, flags, millis_between_reports)
+    }
+
+    pub fn enable_game_rotation_vector(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(SENSOR_REPORTID_GAME_ROTATION_VECTOR, flags, millis_between_reports)
+    }
+
+    pub fn enable_arvr_stabilised_rotation_vector(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(SENSOR_REPORTID_ARVR_STABILISED_ROTATION_VECTOR, flags, millis_between_reports)
+    }
+
+    pub fn enable_arvr_stabilised_game_rotation_vector(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(SENSOR_REPORTID_ARVR_STABILISED_GAME_ROTATION_VECTOR, flags, millis_between_reports)
+    }
+
+    pub fn enable_geomagnetic_rotation_vector(
+        &mut self,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_report(
+            SENSOR_REPORTID_GEOMAG_VECTOR,
+            flags,
+            millis_between_reports,
+        )
+    }
+
+    /// Enable a particular report
+    pub fn enable_report(
+        &mut self,
+        report_id: u8,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        let micros_between_reports: u32 =
+            (millis_between_reports as u32) * 1000;
+        self.enable_batched_report(report_id, flags, micros_between_reports, 0)
+    }
+
+    /// Enable a particular report, allowing the sensor hub to hold
+    /// samples for up to `batch_interval_us` before delivering them
+    /// together in large batched packets.
+    /// A batch interval of zero delivers each sample as soon as it is ready.
+    /// Use `drain_batched_reports` to receive every batched sample.
+    pub fn enable_batched_report(
+        &mut self,
+        report_id: u8,
+        flags: u8,
+        micros_between_reports: u32,
+        batch_interval_us: u32,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_feature(&FeatureConfig {
+            report_id,
+            flags,
+            micros_between_reports,
+            batch_interval_us,
+            ..Default::default()
+        })
+    }
+
+    /// Stop the sensor hub from sending a particular report
+    pub fn disable_report(
+        &mut self,
+        report_id: u8,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_feature(&FeatureConfig {
+            report_id,
+            ..Default::default()
+        })
+    }
+
+    /// Configure a feature with full control over its settings.
+    /// Every enabled feature is remembered (up to `MAX_ENABLED_FEATURES`)
+    /// so that it can be reapplied after the sensor hub resets.
+    /// Features beyond that are still configured, but only counted in
+    /// `Stats::unremembered_features`.
+    pub fn enable_feature(
+        &mut self,
+        config: &FeatureConfig,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body = self.prepare_feature::<SE>(config)?;
+
+        //we simply blast out this configuration command and assume it'll succeed
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body)?;
+        // any error or success in configuration will arrive some time later
+
+        self.remember_feature(config);
+        Ok(())
+    }
+
+    /// Send the configuration of every enabled feature to the sensor hub
+    /// again, for instance after it has reset.
+    pub fn reapply_features(&mut self) -> Result<(), WrapperError<SE>> {
+        let enabled_features = self.enabled_features;
+        for config in enabled_features.iter().flatten() {
+            self.enable_feature(config)?;
+        }
+        Ok(())
+    }
+
+    /// Receive and handle every packet the sensor hub has queued,
+    /// passing each input report (including batched reports)
+    /// to `sink` along with its reconstructed timestamp.
+    /// Returns the number of packets handled.
+    pub fn drain_batched_reports(
+        &mut self,
+        delay: &mut impl DelayNs,
+        max_ms: u8,
+        mut sink: impl FnMut(&SensorSample),
+    ) -> Result<u32, WrapperError<SE>> {
+        let mut total_handled: u32 = 0;
+        loop {
+            let received_len =
+                self.receive_packet_with_timeout(delay, max_ms)?;
+            if received_len == 0 {
+                break;
+            }
+            self.dispatch_received_packet(received_len, &mut sink)?;
+            total_handled += 1;
+        }
+        Ok(total_handled)
+    }
+
+    /// Ask the sensor hub to immediately deliver any batched reports
+    /// for `report_id`, then handle incoming messages until the hub
+    /// confirms that the flush has completed.
+    pub fn flush(
+        &mut self,
+        report_id: u8,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        println!("flush 0x{:X}", report_id);
+        let cmd_body: [u8; 2] = [SHUB_FORCE_SENSOR_FLUSH, report_id];
+
+        self.last_flush_completed = None;
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body)?;
+
+        // batched reports are delivered ahead of the flush completed response
+        self.handle_messages_until(delay, |shub| {
+            shub.last_flush_completed == Some(report_id)
+        })
+    }
+
+    /// Ask the sensor hub to send its full advertisement again,
+    /// and wait for it to arrive
+    pub fn request_advertisement(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body: [u8; 2] = [CMD_GET_ADVERTISEMENT, ADVERTISE_ALL];
+
+        self.advert_received = false;
+        self.send_packet(CHANNEL_COMMAND, &cmd_body)?;
+        self.handle_messages_until(delay, |shub| shub.advert_received)
+    }
+
+    /// Ask the sensor hub for its list of SHTP errors,
+    /// and wait for it to arrive.
+    /// The errors are then available from `error_list`.
+    pub fn request_error_list(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body: [u8; 1] = [CMD_SEND_ERROR_LIST];
+
+        self.error_list_received = false;
+        self.error_list_requested = true;
+        self.send_packet(CHANNEL_COMMAND, &cmd_body)?;
+        self.handle_messages_until(delay, |shub| shub.error_list_received)
+    }
+
+    /// Read the sensor hub's statistics for one sensor:
+    /// how many samples were offered, accepted, produced while on,
+    /// and attempted. Useful to diagnose dropped samples.
+    pub fn get_counts(
+        &mut self,
+        report_id: u8,
+        delay: &mut impl DelayNs,
+    ) -> Result<SensorCounts, WrapperError<SE>> {
+        let responses = self.execute_command(
+            SH2_CMD_COUNTS,
+            &[SH2_COUNTS_GET, report_id],
+            2,
+            delay,
+        )?;
+        Ok(SensorCounts::from_responses(responses))
+    }
+
+    /// Reset the sensor hub's statistics for one sensor
+    pub fn clear_counts(
+        &mut self,
+        report_id: u8,
+    ) -> Result<(), WrapperError<SE>> {
+        self.send_command(SH2_CMD_COUNTS, &[SH2_COUNTS_CLEAR, report_id])?;
+        // no response will come to carry the request along
+        self.flush_outbound()
+    }
 
-        let res = self.receive_packet_with_timeout(delay, max_ms);
-        if res.is_ok() {
-            let received_len = res.unwrap_or(0);
-            if received_len > 0 {
-                msg_count += 1;
-                self.handle_received_packet(received_len);
+    /// Ask the sensor hub which clock source it is running from
+    pub fn oscillator_type(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<OscillatorType, WrapperError<SE>> {
+        let responses =
+            self.execute_command(SH2_CMD_GET_OSC_TYPE, &[], 1, delay)?;
+        Ok(OscillatorType::from(responses[0].values[0]))
+    }
+
+    /// Send an SH-2 command request and collect `response_count`
+    /// responses matching its command and sequence number,
+    /// waiting no longer than the command's timeout.
+    /// Only polls that found nothing to read count towards the timeout,
+    /// so that a stream of input reports cannot cut it short.
+    fn execute_command(
+        &mut self,
+        command: u8,
+        params: &[u8],
+        response_count: usize,
+        delay: &mut impl DelayNs,
+    ) -> Result<&[CommandResponse], WrapperError<SE>> {
+        let cmd_body = self.send_command(command, params)?;
+        let mut wait = self.start_command_wait(&cmd_body, response_count);
+        while !self.command_complete::<SE>(&wait)? {
+            let rc = self.handle_one_message(delay, COMMAND_POLL_MS);
+            self.command_polled(&mut wait, rc)?;
+        }
+        Ok(self.command_responses())
+    }
+
+    /// Send an SH-2 command request with up to nine parameters,
+    /// returning the request sent
+    fn send_command(
+        &mut self,
+        command: u8,
+        params: &[u8],
+    ) -> Result<[u8; SHUB_COMMAND_REQ_LENGTH], WrapperError<SE>> {
+        let cmd_body = self.command_request::<SE>(command, params)?;
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body)?;
+        Ok(cmd_body)
+    }
+
+    /// Handle incoming messages until `done` is satisfied,
+    /// or until no more messages arrive
+    fn handle_messages_until(
+        &mut self,
+        delay: &mut impl DelayNs,
+        done: impl Fn(&Self) -> bool,
+    ) -> Result<(), WrapperError<SE>> {
+        while !done(self) {
+            let msg_count = self.handle_one_message(delay, 150u8)?;
+            if msg_count < 1 {
+                increment(&mut self.stats.timeouts);
+                return Err(WrapperError::NoDataAvailable);
             }
+        }
+        Ok(())
+    }
+
+    /// Send packet from our packet send buf
+    fn send_packet(
+        &mut self,
+        channel: u8,
+        body_data: &[u8],
+    ) -> Result<usize, WrapperError<SE>> {
+        let packet_length = self.prep_send_packet::<SE>(channel, body_data)?;
+        let rc = self
+            .sensor_interface
+            .write_packet(&self.packet_send_buf[..packet_length]);
+        self.count_comm_error(rc)?;
+        Ok(packet_length)
+    }
+
+    /// Read one packet into the receive buffer
+    pub(crate) fn receive_packet_with_timeout(
+        &mut self,
+        delay: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, WrapperError<SE>> {
+        //
+        // println!("r_p");
+
+        self.packet_recv_buf[0] = 0;
+        self.packet_recv_buf[1] = 0;
+        let rc = self.sensor_interface.read_with_timeout(
+            &mut self.packet_recv_buf,
+            delay,
+            max_ms,
+        );
+        let packet_len = self.count_comm_error(rc)?;
+        self.count_packet_received(packet_len);
+
+        self.last_packet_len_received = packet_len;
+        //
+        // println!("recv {}", packet_len);
+
+        Ok(packet_len)
+    }
+
+    /// Verify that the sensor returns an expected chip ID
+    /// and runs a supported firmware version
+    fn verify_product_id(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        println!("request PID...");
+        let cmd_body = self.start_product_id_request();
+
+        // for some reason, reading PID right sending request does not work with i2c
+        if self.sensor_interface.requires_soft_reset() {
+            self.send_packet(CHANNEL_HUB_CONTROL, cmd_body.as_ref())?;
         } else {
-            println!("handle1 err {:?}", res);
+            let response_size = self.send_and_receive_packet(
+                CHANNEL_HUB_CONTROL,
+                cmd_body.as_ref(),
+            )?;
+            if response_size > 0 {
+                self.handle_received_packet(response_size)?;
+            }
+        };
+
+        // process all incoming messages until we get a product id (or no more data)
+        let mut _iterations: u8 = 0;
+        while !self.prod_id_verified {
+            println!("read PID");
+            println!("Tried reading PID {} times", _iterations);
+            let msg_count = self.handle_one_message(delay, 150u8)?;
+            if msg_count < 1 {
+                break;
+            }
+            _iterations = _iterations.wrapping_add(1);
         }
 
-        msg_count
-    }
+        self.check_product_id()
+    }
+
+    /// Read normalized quaternion:
+    /// QX normalized quaternion – X, or Heading | range: 0.0 – 1.0 ( ±π )
+    /// QY normalized quaternion – Y, or Pitch   | range: 0.0 – 1.0 ( ±π/2 )
+    /// QZ normalized quaternion – Z, or Roll    | range: 0.0 – 1.0 ( ±π )
+    /// QW normalized quaternion – W, or 0.0     | range: 0.0 – 1.0
+    pub fn rotation_quaternion(&self) -> Result<[f32; 4], WrapperError<SE>> {
+        Ok(self.rotation_quaternion)
+    }
+
+    /// Read linear acceleration (m/s^2)
+    pub fn linear_accel(&self) -> Result<[f32; 3], WrapperError<SE>> {
+        Ok(self.linear_accel)
+    }
+
+    /// Read gyroscope data (rad/s)
+    pub fn gyro(&self) -> Result<[f32; 3], WrapperError<SE>> {
+        Ok(self.gyro)
+    }
+
+    /// Tell the sensor to reset.
+    /// Normally applications should not need to call this directly,
+    /// as it is called during `init`.
+    pub fn soft_reset(&mut self) -> Result<(), WrapperError<SE>> {
+        //
+        // println!("soft_reset");
+        self.expect_reset();
+        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_RESET];
+        // send command packet and ignore received packets
+        let received_len =
+            self.send_and_receive_packet(CHANNEL_EXECUTABLE, data.as_ref())?;
+        if received_len > 0 {
+            self.handle_received_packet(received_len)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reinitialize part of the sensor hub with the SH-2 initialize command.
+    /// This is a lighter-weight recovery than `soft_reset`.
+    /// Enabled features may need to be configured again with
+    /// `reapply_features` afterwards.
+    pub fn reinitialize(
+        &mut self,
+        subsystem: InitSubsystem,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        // the hub may announce a reset while reinitializing
+        self.expect_reset();
+        let rc = self
+            .execute_command(SH2_CMD_INITIALIZE, &[subsystem.into()], 1, delay)
+            .and_then(Self::check_initialize_status);
+        self.reset_expected = false;
+        rc
+    }
+
+    /// Put the sensor hub to sleep: every sensor stops except
+    /// those configured to be always on.
+    /// Reports cannot be configured again until `wake` is called.
+    pub fn sleep(&mut self) -> Result<(), WrapperError<SE>> {
+        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_SLEEP];
+        self.send_packet(CHANNEL_EXECUTABLE, data.as_ref())?;
+        // the application may not read from the sensor hub for a while
+        self.flush_outbound()?;
+        self.power_state = PowerState::Sleep;
+        Ok(())
+    }
+
+    /// Wake the sensor hub from sleep, resuming every enabled report
+    pub fn wake(&mut self) -> Result<(), WrapperError<SE>> {
+        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_ON];
+        self.send_packet(CHANNEL_EXECUTABLE, data.as_ref())?;
+        // the sensor hub does not answer, and may have nothing to send
+        self.flush_outbound()?;
+        self.power_state = PowerState::On;
+        Ok(())
+    }
+
+    /// Send any packets the sensor interface has held back,
+    /// to be sent along with the next packet read.
+    /// Call this before `free` if no more packets will be read.
+    pub fn flush_outbound(&mut self) -> Result<(), WrapperError<SE>> {
+        let rc = self.sensor_interface.flush_outbound();
+        self.count_comm_error(rc)
+    }
+
+    /// Send a packet and receive the response
+    fn send_and_receive_packet(
+        &mut self,
+        channel: u8,
+        body_data: &[u8],
+    ) -> Result<usize, WrapperError<SE>> {
+        let send_packet_length =
+            self.prep_send_packet::<SE>(channel, body_data)?;
+        //
+        // println!("srcv {} ...", send_packet_length);
+
+        let rc = self.sensor_interface.send_and_receive_packet(
+            &self.packet_send_buf[..send_packet_length],
+            &mut self.packet_recv_buf,
+        );
+        let recv_packet_length = self.count_comm_error(rc)?;
+        self.count_packet_received(recv_packet_length);
+
+        println!("srcv {} {}", send_packet_length, recv_packet_length);
 
-    /// Receive and ignore one message,
-    /// returning the size of the packet received or zero
-    /// if there was no packet to read.
-    pub fn eat_one_message(&mut self, delay: &mut impl DelayNs) -> usize {
-        let res = self.receive_packet_with_timeout(delay, 150);
-        return if let Ok(received_len) = res {
-            println!("e1 {}", received_len);
-            received_len
-        } else {
-            println!("e1 err {:?}", res);
-            0
-        };
+        Ok(recv_packet_length)
     }
+}
 
-    fn handle_advertise_response(&mut self, received_len: usize) {
+/// Handling of received packets and bookkeeping that does not depend
+/// on how we communicate with the sensor hub
+impl<SI, const SEND_BUF_LEN: usize, const RECV_BUF_LEN: usize>
+    BNO080<SI, SEND_BUF_LEN, RECV_BUF_LEN>
+{
+    fn handle_advertise_response<SE>(
+        &mut self,
+        received_len: usize,
+    ) -> Result<(), WrapperError<SE>> {
         let payload_len = received_len - PACKET_HEADER_LENGTH;
         let payload = &self.packet_recv_buf[PACKET_HEADER_LENGTH..received_len];
         let mut cursor: usize = 1; //skip response type
@@ -182,6 +1246,12 @@ where
         println!("AdvRsp: {}", payload_len);
 
         while cursor < payload_len {
+            // each tag is followed by the length of its value
+            if cursor + 2 > payload_len {
+                return Err(WrapperError::TruncatedReport(
+                    CMD_RESP_ADVERTISEMENT,
+                ));
+            }
             let _tag: u8 = payload[cursor];
             cursor += 1;
             let len: u8 = payload[cursor];
@@ -191,6 +1261,7 @@ where
         }
 
         self.advert_received = true;
+        Ok(())
     }
 
     fn read_u8_at_cursor(msg: &[u8], cursor: &mut usize) -> u8 {
@@ -205,97 +1276,214 @@ where
         val
     }
 
-    fn try_read_i16_at_cursor(msg: &[u8], cursor: &mut usize) -> Option<i16> {
-        let remaining = msg.len() - *cursor;
-        if remaining >= 2 {
-            let val = (msg[*cursor] as i16) | ((msg[*cursor + 1] as i16) << 8);
-            *cursor += 2;
-            Some(val)
-        } else {
-            None
-        }
+    fn read_u16_at_cursor(msg: &[u8], cursor: &mut usize) -> u16 {
+        let val = (msg[*cursor] as u16) | ((msg[*cursor + 1] as u16) << 8);
+        *cursor += 2;
+        val
     }
 
-    /// Read data values from a single input report
+    fn read_u32_at_cursor(msg: &[u8], cursor: &mut usize) -> u32 {
+        let val = (msg[*cursor] as u32)
+            | ((msg[*cursor + 1] as u32) << 8)
+            | ((msg[*cursor + 2] as u32) << 16)
+            | ((msg[*cursor + 3] as u32) << 24);
+        *cursor += 4;
+        val
+    }
+
+    /// Read data values from a single input report of `report_len` bytes.
+    /// `reference_delta` is the packet timebase in 100 microsecond ticks.
     fn handle_one_input_report(
         outer_cursor: usize,
+        report_len: usize,
         msg: &[u8],
-    ) -> (usize, u8, i16, i16, i16, i16, i16) {
+        reference_delta: i32,
+    ) -> SensorSample {
         let mut cursor = outer_cursor;
 
-        let feature_report_id = Self::read_u8_at_cursor(msg, &mut cursor);
-        let _rep_seq_num = Self::read_u8_at_cursor(msg, &mut cursor);
-        let _rep_status = Self::read_u8_at_cursor(msg, &mut cursor);
-        let _delay = Self::read_u8_at_cursor(msg, &mut cursor);
-
-        let data1: i16 = Self::read_i16_at_cursor(msg, &mut cursor);
-        let data2: i16 = Self::read_i16_at_cursor(msg, &mut cursor);
-        let data3: i16 = Self::read_i16_at_cursor(msg, &mut cursor);
-        let data4: i16 =
-            Self::try_read_i16_at_cursor(msg, &mut cursor).unwrap_or(0);
-        let data5: i16 =
-            Self::try_read_i16_at_cursor(msg, &mut cursor).unwrap_or(0);
+        let report_id = Self::read_u8_at_cursor(msg, &mut cursor);
+        let sequence = Self::read_u8_at_cursor(msg, &mut cursor);
+        let status = Self::read_u8_at_cursor(msg, &mut cursor);
+        let delay_lsb = Self::read_u8_at_cursor(msg, &mut cursor);
+        // the upper six bits of the 14-bit report delay share the status byte
+        let delay: i32 = (((status & 0xFC) as i32) << 6) | (delay_lsb as i32);
+
+        let report_end = outer_cursor + report_len;
+        let mut data = [0i16; 5];
+        for val in data.iter_mut() {
+            if cursor + 2 > report_end {
+                break;
+            }
+            *val = Self::read_i16_at_cursor(msg, &mut cursor);
+        }
 
-        (cursor, feature_report_id, data1, data2, data3, data4, data5)
+        SensorSample {
+            report_id,
+            sequence,
+            status: status & 0x03,
+            timestamp_us: reference_delta
+                .saturating_add(delay)
+                .saturating_mul(100),
+            data,
+        }
     }
 
     /// Handle parsing of an input report packet,
-    /// which may include multiple input reports
-    fn handle_sensor_reports(&mut self, received_len: usize) {
+    /// which may include multiple input reports,
+    /// passing each decoded report to `sink`
+    fn handle_sensor_reports(
+        &mut self,
+        received_len: usize,
+        sink: &mut impl FnMut(&SensorSample),
+    ) {
         // Sensor input packets have the form:
-        // [u8; 5]  timestamp in microseconds for the packet?
+        // [u8; 5]  base timestamp reference (0xFB) for the packet
         // a sequence of n reports, each with four byte header
         // u8 report ID
         // u8 sequence number of report
-
-        // let mut report_count = 0;
-        let mut outer_cursor: usize = PACKET_HEADER_LENGTH + 5; //skip header, timestamp
-                                                                //TODO need to skip more above for a payload-level timestamp??
-        if received_len < outer_cursor {
-            println!("bad lens: {} < {}", received_len, outer_cursor);
-            return;
-        }
-
-        let payload_len = received_len - outer_cursor;
+        // u8 status, including the upper bits of the report delay
+        // u8 report delay LSB
+        // Batched packets may also include timestamp rebase (0xFA) records.
+        let mut cursor: usize = PACKET_HEADER_LENGTH;
+        // timebase of the following reports, in 100 microsecond ticks,
+        // relative to the moment the hub signalled this packet
+        let mut reference_delta: i32 = 0;
 
         // there may be multiple reports per payload
-        while outer_cursor < payload_len {
-            //let start_cursor = outer_cursor;
-            let (inner_cursor, report_id, data1, data2, data3, data4, data5) =
-                Self::handle_one_input_report(
-                    outer_cursor,
-                    &self.packet_recv_buf[..received_len],
+        while cursor < received_len {
+            let report_id = self.packet_recv_buf[cursor];
+            let report_len = match sensor_report_length(report_id) {
+                Some(len) => len,
+                None => {
+                    // without its length we cannot find the next report
+                    println!("uhr: 0x{:X}", report_id);
+                    increment(&mut self.stats.unknown_reports);
+                    break;
+                }
+            };
+            if cursor + report_len > received_len {
+                // the remainder of an oversized packet was dropped
+                println!(
+                    "bad lens: {} < {}",
+                    received_len,
+                    cursor + report_len
                 );
-            outer_cursor = inner_cursor;
-            // report_count += 1;
+                break;
+            }
+
+            let mut inner_cursor = cursor + 1;
             match report_id {
-                SENSOR_REPORTID_ROTATION_VECTOR => {
-                    self.update_rotation_quaternion(
-                        data1, data2, data3, data4,
+                SHUB_BASE_TIMESTAMP_REF => {
+                    // positive base delta means the samples preceded the interrupt
+                    let base_delta = Self::read_u32_at_cursor(
+                        &self.packet_recv_buf,
+                        &mut inner_cursor,
                     );
-                    self.update_rotation_quaternion_acc(data5);
-                }
-                SENSOR_REPORTID_LINEAR_ACCEL => {
-                    self.update_linear_accel(data1, data2, data3);
+                    reference_delta = (base_delta as i32).wrapping_neg();
                 }
-                SENSOR_REPORTID_GYRO => {
-                    self.update_gyro_cal(data1, data2, data3);
-                }
-                SENSOR_REPORTID_ARVR_STABILISED_ROTATION_VECTOR => {
-                    self.update_rotation_quaternion(data1, data2, data3, data4);
-                    self.update_rotation_quaternion_acc(data5);
-                }
-                SENSOR_REPORTID_ARVR_STABILISED_GAME_ROTATION_VECTOR => {
-                    self.update_rotation_quaternion(data1, data2, data3, data4);
+                SHUB_TIMESTAMP_REBASE => {
+                    let rebase_delta = Self::read_u32_at_cursor(
+                        &self.packet_recv_buf,
+                        &mut inner_cursor,
+                    );
+                    reference_delta =
+                        reference_delta.wrapping_add(rebase_delta as i32);
                 }
                 _ => {
-                    // debug_println!("uhr: {:X}", report_id);
-                    // debug_println!("uhr: 0x{:X} {:?}  ", report_id, &self.packet_recv_buf[start_cursor..start_cursor+5]);
+                    let sample = Self::handle_one_input_report(
+                        cursor,
+                        report_len,
+                        &self.packet_recv_buf[..received_len],
+                        reference_delta,
+                    );
+                    self.track_report_sequence(&sample);
+                    self.update_from_sample(&sample);
+                    sink(&sample);
                 }
             }
+            cursor += report_len;
+        }
+    }
+
+    /// Count any input reports missing before `sample`
+    fn track_report_sequence(&mut self, sample: &SensorSample) {
+        let idx = sample.report_id as usize;
+        if idx >= NUM_TRACKED_REPORT_IDS {
+            return;
         }
+        if let Some(last_seq) = self.report_sequence_numbers[idx] {
+            let gap = sample.sequence.wrapping_sub(last_seq).wrapping_sub(1);
+            self.dropped_reports[idx] =
+                self.dropped_reports[idx].saturating_add(gap as u32);
+            self.stats.dropped_reports =
+                self.stats.dropped_reports.saturating_add(gap as u32);
+        }
+        self.report_sequence_numbers[idx] = Some(sample.sequence);
+    }
+
+    /// Count any packets missing on `channel` before the packet numbered
+    /// `seq_num`, carrying `report_id`
+    fn track_packet_sequence(
+        &mut self,
+        channel: u8,
+        report_id: u8,
+        seq_num: u8,
+    ) {
+        let idx = channel as usize;
+        if idx >= NUM_CHANNELS {
+            return;
+        }
+        if channel == CHANNEL_EXECUTABLE
+            && report_id == EXECUTABLE_DEVICE_RESP_RESET_COMPLETE
+        {
+            // the sensor hub numbers everything from zero after a reset
+            self.forget_rx_sequence_numbers();
+        } else if channel == CHANNEL_COMMAND
+            && report_id == CMD_RESP_ADVERTISEMENT
+        {
+            // a reset is advertised before the reset complete response
+            self.rx_sequence_numbers[idx] = None;
+        }
+
+        if let Some(last_seq) = self.rx_sequence_numbers[idx] {
+            let gap = seq_num.wrapping_sub(last_seq).wrapping_sub(1);
+            self.stats.dropped_packets[idx] =
+                self.stats.dropped_packets[idx].saturating_add(gap as u32);
+        }
+        self.rx_sequence_numbers[idx] = Some(seq_num);
+    }
+
+    /// Stop expecting particular sequence numbers for received data
+    fn forget_rx_sequence_numbers(&mut self) {
+        self.rx_sequence_numbers = [None; NUM_CHANNELS];
+        self.report_sequence_numbers = [None; NUM_TRACKED_REPORT_IDS];
+    }
 
-        //debug_println!("report_count: {}",report_count);
+    /// Update our latest sensor values from a single input report
+    fn update_from_sample(&mut self, sample: &SensorSample) {
+        let [data1, data2, data3, data4, data5] = sample.data;
+        match sample.report_id {
+            SENSOR_REPORTID_ROTATION_VECTOR => {
+                self.update_rotation_quaternion(data1, data2, data3, data4);
+                self.update_rotation_quaternion_acc(data5);
+            }
+            SENSOR_REPORTID_LINEAR_ACCEL => {
+                self.update_linear_accel(data1, data2, data3);
+            }
+            SENSOR_REPORTID_GYRO => {
+                self.update_gyro_cal(data1, data2, data3);
+            }
+            SENSOR_REPORTID_ARVR_STABILISED_ROTATION_VECTOR => {
+                self.update_rotation_quaternion(data1, data2, data3, data4);
+                self.update_rotation_quaternion_acc(data5);
+            }
+            SENSOR_REPORTID_ARVR_STABILISED_GAME_ROTATION_VECTOR => {
+                self.update_rotation_quaternion(data1, data2, data3, data4);
+            }
+            _ => {
+                // debug_println!("uhr: {:X}", report_id);
+            }
+        }
     }
 
     /// Given a set of quaternion values in the Q-fixed-point format,
@@ -343,35 +1531,200 @@ where
         self.gyro = [x, y, z];
     }
 
-    /// Handle one or more errors sent in response to a command
+    /// Decode every product ID record in a product ID response:
+    /// the sensor hub sends one record per firmware component
+    fn handle_prod_id_response(&mut self, received_len: usize) {
+        let mut cursor = PACKET_HEADER_LENGTH;
+        while cursor + PROD_ID_RECORD_LENGTH <= received_len
+            && self.packet_recv_buf[cursor] == SHUB_PROD_ID_RESP
+        {
+            let msg = &self.packet_recv_buf[..received_len];
+            let mut inner_cursor = cursor + 1;
+            let reset_cause = Self::read_u8_at_cursor(msg, &mut inner_cursor);
+            let sw_version_major =
+                Self::read_u8_at_cursor(msg, &mut inner_cursor);
+            let sw_version_minor =
+                Self::read_u8_at_cursor(msg, &mut inner_cursor);
+            let sw_part_number =
+                Self::read_u32_at_cursor(msg, &mut inner_cursor);
+            let sw_build_number =
+                Self::read_u32_at_cursor(msg, &mut inner_cursor);
+            let sw_version_patch =
+                Self::read_u16_at_cursor(msg, &mut inner_cursor);
+
+            println!("PID_RESP {}.{}", sw_version_major, sw_version_minor);
+            self.product_info.push(ProductIdRecord {
+                reset_cause: ResetCause::from(reset_cause),
+                sw_version_major,
+                sw_version_minor,
+                sw_part_number,
+                sw_build_number,
+                sw_version_patch,
+            });
+            cursor += PROD_ID_RECORD_LENGTH;
+        }
+
+        self.prod_id_verified = !self.product_info.records().is_empty();
+
+        if self.awaiting_reset_cause && self.prod_id_verified {
+            self.awaiting_reset_cause = false;
+            // fill in the cause if the reset event has not been taken yet
+            if let Some(Event::Reset(_)) = self.pending_event {
+                self.pending_event =
+                    Some(Event::Reset(self.product_info.reset_cause()));
+            }
+        }
+    }
+
+    /// Decode the results carried by a command response
+    fn handle_command_response<SE>(
+        &mut self,
+        received_len: usize,
+    ) -> Result<(), WrapperError<SE>> {
+        // Command responses have the form:
+        // u8 report ID, u8 sequence number, u8 command,
+        // u8 command sequence number, u8 response sequence number,
+        // [u8; 11] response values R0..R10
+        if received_len < PACKET_HEADER_LENGTH + SHUB_COMMAND_RESP_LENGTH {
+            return Err(WrapperError::TruncatedReport(SHUB_COMMAND_RESP));
+        }
+        let cmd_resp = self.packet_recv_buf[PACKET_HEADER_LENGTH + 2];
+        if cmd_resp == SH2_STARTUP_INIT_UNSOLICITED
+            || cmd_resp == SH2_CMD_INITIALIZE
+        {
+            self.init_received = true;
+        }
+        println!("CMD_RESP: 0x{:X}", cmd_resp);
+        if cmd_resp == SH2_STARTUP_INIT_UNSOLICITED {
+            // the last message sent by the hub after a reset,
+            // which has already been checked if we saw it complete
+            if !core::mem::take(&mut self.reset_in_progress) {
+                self.check_unexpected_reset();
+            }
+            self.reset_expected = false;
+        }
+
+        let msg = &self.packet_recv_buf[..received_len];
+        let mut resp = CommandResponse {
+            command: msg[PACKET_HEADER_LENGTH + 2],
+            command_sequence: msg[PACKET_HEADER_LENGTH + 3],
+            response_sequence: msg[PACKET_HEADER_LENGTH + 4],
+            ..Default::default()
+        };
+        let values_start = PACKET_HEADER_LENGTH + 5;
+        let values_end = PACKET_HEADER_LENGTH + SHUB_COMMAND_RESP_LENGTH;
+        resp.values.copy_from_slice(&msg[values_start..values_end]);
+
+        // only keep responses to the request we are waiting on
+        if self.pending_command != Some((resp.command, resp.command_sequence))
+            || self.command_response_count >= MAX_COMMAND_RESPONSES
+        {
+            return Ok(());
+        }
+        self.command_responses[self.command_response_count] = resp;
+        self.command_response_count += 1;
+        Ok(())
+    }
+
+    /// Called whenever the sensor hub announces that it has reset.
+    /// A reset we did not ask for silently disables every report,
+    /// so report it at once and request the product ID to learn
+    /// why it happened.
+    fn check_unexpected_reset(&mut self) {
+        if !self.initialized || self.reset_expected {
+            return;
+        }
+        println!("unexpected reset");
+
+        // the cause is not known until the product ID arrives
+        self.pending_event = Some(Event::Reset(ResetCause::NotApplicable));
+        self.awaiting_reset_cause = true;
+        self.product_info = ProductInfo::default();
+        self.product_id_request_pending = true;
+        // configuring features must wait until the packet is handled
+        self.reapply_pending = self.reapply_on_reset;
+    }
+
+    /// Handle one or more errors sent in response to a command.
+    /// An error list we did not request is kept all the same,
+    /// counted in `Stats::hub_errors` and raised as an `Event::HubError`.
     fn handle_cmd_resp_error_list(&mut self, received_len: usize) {
         let payload_len = received_len - PACKET_HEADER_LENGTH;
         let payload = &self.packet_recv_buf[PACKET_HEADER_LENGTH..received_len];
 
         self.error_list_received = true;
-        for cursor in 1..payload_len {
-            let err: u8 = payload[cursor];
-            self.last_error_received = err;
-
+        self.error_list_len = 0;
+        for &err in payload.iter().take(payload_len).skip(1) {
             println!("lerr: {:x}", err);
+            if self.error_list_len < MAX_ERROR_LIST_LEN {
+                self.error_list[self.error_list_len] = ShtpError::from(err);
+                self.error_list_len += 1;
+            }
+        }
+
+        if !self.error_list_requested {
+            let first_error = self
+                .error_list()
+                .iter()
+                .find(|&&err| err != ShtpError::NoError);
+            if let Some(&err) = first_error {
+                increment(&mut self.stats.hub_errors);
+                self.pending_event.get_or_insert(Event::HubError(err));
+            }
         }
+        self.error_list_requested = false;
     }
 
-    pub fn handle_received_packet(&mut self, received_len: usize) {
-        let msg = &self.packet_recv_buf[..received_len];
+    /// Decode a received packet, passing any input reports it contains
+    /// to `sink` and counting it if it is malformed
+    fn process_received_packet<SE>(
+        &mut self,
+        received_len: usize,
+        sink: &mut impl FnMut(&SensorSample),
+    ) -> Result<(), WrapperError<SE>> {
+        let rc = self.decode_received_packet(received_len, sink);
+        if let Err(
+            WrapperError::MalformedPacket
+            | WrapperError::TruncatedReport(_)
+            | WrapperError::UnknownChannel(_),
+        ) = rc
+        {
+            increment(&mut self.stats.malformed_packets);
+        }
+        if let Err(WrapperError::OversizedPacket(_)) = rc {
+            increment(&mut self.stats.oversized_packets);
+        }
+        rc
+    }
+
+    fn decode_received_packet<SE>(
+        &mut self,
+        received_len: usize,
+        sink: &mut impl FnMut(&SensorSample),
+    ) -> Result<(), WrapperError<SE>> {
+        if received_len < PACKET_HEADER_LENGTH {
+            return Err(WrapperError::MalformedPacket);
+        }
+        let msg = &self.packet_recv_buf[..received_len.min(RECV_BUF_LEN)];
         let chan_num = msg[2];
-        //let _seq_num =  msg[3];
+        let seq_num = msg[3];
         let report_id: u8 = if received_len > PACKET_HEADER_LENGTH {
             msg[4]
         } else {
             0
         };
+        self.track_packet_sequence(chan_num, report_id, seq_num);
+        if received_len > RECV_BUF_LEN {
+            // the sensor interface drained the rest of it
+            return Err(WrapperError::OversizedPacket(received_len));
+        }
+        let msg = &self.packet_recv_buf[..received_len];
 
         self.last_chan_received = chan_num;
         match chan_num {
             CHANNEL_COMMAND => match report_id {
                 CMD_RESP_ADVERTISEMENT => {
-                    self.handle_advertise_response(received_len);
+                    self.handle_advertise_response::<SE>(received_len)?;
                 }
                 CMD_RESP_ERROR_LIST => {
                     self.handle_cmd_resp_error_list(received_len);
@@ -380,46 +1733,45 @@ where
                     self.last_command_chan_rid = report_id;
 
                     println!("unh cmd: {}", report_id);
+                    increment(&mut self.stats.unknown_reports);
                 }
             },
             CHANNEL_EXECUTABLE => match report_id {
                 EXECUTABLE_DEVICE_RESP_RESET_COMPLETE => {
                     self.device_reset = true;
+                    // the sensor hub always wakes up from a reset
+                    self.power_state = PowerState::On;
 
                     println!("resp_reset {}", 1);
+                    increment(&mut self.stats.resets);
+                    self.check_unexpected_reset();
+                    self.reset_in_progress = true;
                 }
                 _ => {
                     self.last_exec_chan_rid = report_id;
 
                     println!("unh exe: {:x}", report_id);
+                    increment(&mut self.stats.unknown_reports);
                 }
             },
             CHANNEL_HUB_CONTROL => {
                 match report_id {
                     SHUB_COMMAND_RESP => {
                         // 0xF1 / 241
-                        let cmd_resp = msg[6];
-                        if cmd_resp == SH2_STARTUP_INIT_UNSOLICITED {
-                            self.init_received = true;
-                        } else if cmd_resp == SH2_INIT_SYSTEM {
-                            self.init_received = true;
-                        }
-
-                        println!("CMD_RESP: 0x{:X}", cmd_resp);
+                        self.handle_command_response::<SE>(received_len)?;
                     }
-                    #[cfg_attr(not(feature = "defmt-03"), allow(unused))]
                     SHUB_PROD_ID_RESP => {
-                        {
-                            //let reset_cause = msg[4 + 1];
-                            let sw_vers_major = msg[4 + 2];
-                            let sw_vers_minor = msg[4 + 3];
-                            println!(
-                                "PID_RESP {}.{}",
-                                sw_vers_major, sw_vers_minor
-                            );
-                        }
-
-                        self.prod_id_verified = true;
+                        self.handle_prod_id_response(received_len);
+                    }
+                    SHUB_FLUSH_COMPLETED | SHUB_GET_FEATURE_RESP
+                        if received_len < PACKET_HEADER_LENGTH + 2 =>
+                    {
+                        return Err(WrapperError::TruncatedReport(report_id));
+                    }
+                    SHUB_FLUSH_COMPLETED => {
+                        // 0xEF
+                        self.last_flush_completed = Some(msg[5]);
+                        println!("flush done: {}", msg[5]);
                     }
                     SHUB_GET_FEATURE_RESP => {
                         // 0xFC
@@ -432,333 +1784,388 @@ where
                             report_id,
                             &msg[..PACKET_HEADER_LENGTH]
                         );
+                        increment(&mut self.stats.unknown_reports);
                     }
                 }
             }
-            CHANNEL_SENSOR_REPORTS => {
-                self.handle_sensor_reports(received_len);
+            CHANNEL_SENSOR_REPORTS | CHANNEL_WAKE_REPORTS => {
+                self.handle_sensor_reports(received_len, sink);
+            }
+            _ if chan_num as usize >= NUM_CHANNELS => {
+                return Err(WrapperError::UnknownChannel(chan_num));
             }
             _ => {
-                self.last_chan_received = chan_num;
-
                 println!("unh chan 0x{:X}", chan_num);
             }
         }
+        Ok(())
     }
 
-    /// The BNO080 starts up with all sensors disabled,
-    /// waiting for the application to configure it.
-    pub fn init(
+    /// Prepare a packet for sending, in our send buffer
+    fn prep_send_packet<SE>(
         &mut self,
-        delay_source: &mut impl DelayNs,
-    ) -> Result<(), WrapperError<SE>> {
-        println!("wrapper init");
-
-        //Section 5.1.1.1 : On system startup, the SHTP control application will send
-        // its full advertisement response, unsolicited, to the host.
-        delay_source.delay_ms(1);
-        self.sensor_interface
-            .setup(delay_source)
-            .map_err(WrapperError::CommError)?;
+        channel: u8,
+        body_data: &[u8],
+    ) -> Result<usize, WrapperError<SE>> {
+        let body_len = body_data.len();
 
-        if self.sensor_interface.requires_soft_reset() {
-            delay_source.delay_ms(1);
-            self.soft_reset()?;
-            delay_source.delay_ms(150);
-            self.eat_all_messages(delay_source);
-            delay_source.delay_ms(50);
-            self.eat_all_messages(delay_source);
-        } else {
-            // we only expect two messages after reset:
-            // eat the advertisement response
-            self.eat_one_message(delay_source);
-            // eat the unsolicited initialization response
-            self.eat_one_message(delay_source);
+        let packet_length = body_len + PACKET_HEADER_LENGTH;
+        if packet_length > self.packet_send_buf.len() {
+            return Err(WrapperError::PacketTooLarge(packet_length));
         }
+        let seq_num = self
+            .sequence_numbers
+            .get_mut(channel as usize)
+            .ok_or(WrapperError::UnknownChannel(channel))?;
+        let packet_header = [
+            (packet_length & 0xFF) as u8, //LSB
+            packet_length.shr(8) as u8,   //MSB
+            channel,
+            *seq_num,
+        ];
+        *seq_num = seq_num.wrapping_add(1);
 
-        self.verify_product_id(delay_source)?;
-        //self.eat_all_messages(delay_source);
+        self.packet_send_buf[..PACKET_HEADER_LENGTH]
+            .copy_from_slice(packet_header.as_ref());
+        self.packet_send_buf[PACKET_HEADER_LENGTH..packet_length]
+            .copy_from_slice(body_data);
 
-        Ok(())
+        Ok(packet_length)
     }
 
-    /// Tell the sensor to start reporting the fused rotation vector
-    /// on a regular cadence. Note that the maximum valid update rate
-    /// is 1 kHz, based on the max update rate of the sensor's gyros.
-    pub fn enable_rotation_vector(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(
-            SENSOR_REPORTID_ROTATION_VECTOR,
-            flags,
-            millis_between_reports,
-        )
+    /// Check that the sensor hub is awake to be configured
+    fn check_awake<SE>(&self) -> Result<(), WrapperError<SE>> {
+        if self.power_state == PowerState::Sleep {
+            return Err(WrapperError::SensorAsleep);
+        }
+        Ok(())
     }
 
-    /// Enables reporting of linear acceleration vector.
-    pub fn enable_linear_accel(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(
-            SENSOR_REPORTID_LINEAR_ACCEL,
-            flags,
-            millis_between_reports,
-        )
+    /// Build the Set Feature command configuring a feature as described
+    /// by `config`
+    fn set_feature_command(config: &FeatureConfig) -> [u8; 17] {
+        let micros_between_reports = config.micros_between_reports;
+        let batch_interval_us = config.batch_interval_us;
+        let sensor_specific_config = config.sensor_specific_config;
+        [
+            SHUB_REPORT_SET_FEATURE_CMD,
+            config.report_id,
+            config.flags,                             //feature flags
+            (config.change_sensitivity & 0xFF) as u8, //LSB change sensitivity
+            config.change_sensitivity.shr(8) as u8,   //MSB change sensitivity
+            (micros_between_reports & 0xFFu32) as u8, // LSB report interval, microseconds
+            (micros_between_reports.shr(8) & 0xFFu32) as u8,
+            (micros_between_reports.shr(16) & 0xFFu32) as u8,
+            (micros_between_reports.shr(24) & 0xFFu32) as u8, // MSB report interval
+            (batch_interval_us & 0xFFu32) as u8, // LSB Batch Interval
+            (batch_interval_us.shr(8) & 0xFFu32) as u8,
+            (batch_interval_us.shr(16) & 0xFFu32) as u8,
+            (batch_interval_us.shr(24) & 0xFFu32) as u8, // MSB Batch interval
+            (sensor_specific_config & 0xFFu32) as u8, // LSB sensor-specific config
+            (sensor_specific_config.shr(8) & 0xFFu32) as u8,
+            (sensor_specific_config.shr(16) & 0xFFu32) as u8,
+            (sensor_specific_config.shr(24) & 0xFFu32) as u8, // MSB sensor-specific config
+        ]
     }
 
-    /// Enables reporting of gyroscope data.
-    pub fn enable_gyro(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(SENSOR_REPORTID_GYRO, flags, millis_between_reports)
+    /// Remember `config` in the feature table, replacing any earlier
+    /// configuration of the same feature,
+    /// or forget the feature if `config` disables it
+    fn remember_feature(&mut self, config: &FeatureConfig) {
+        let existing_idx = self.enabled_features.iter().position(|slot| {
+            slot.is_some_and(|cfg| cfg.report_id == config.report_id)
+        });
+        if config.micros_between_reports == 0 {
+            if let Some(idx) = existing_idx {
+                self.enabled_features[idx] = None;
+            }
+            return;
+        }
+        let slot_idx = existing_idx.or_else(|| {
+            self.enabled_features.iter().position(|slot| slot.is_none())
+        });
+        match slot_idx {
+            Some(idx) => self.enabled_features[idx] = Some(*config),
+            None => {
+                println!("feature table full: 0x{:X}", config.report_id);
+                increment(&mut self.stats.unremembered_features);
+            }
+        }
     }
 
-    pub fn enable_game_rotation_vector(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(SENSOR_REPORTID_GAME_ROTATION_VECTOR, flags, millis_between_reports)
+    /// Start initializing: any reset until `finish_init` is expected
+    fn start_init(&mut self) {
+        self.initialized = false;
+        self.expect_reset();
+        self.power_state = PowerState::On;
+        self.forget_rx_sequence_numbers();
     }
 
-    pub fn enable_arvr_stabilised_rotation_vector(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(SENSOR_REPORTID_ARVR_STABILISED_ROTATION_VECTOR, flags, millis_between_reports)
+    /// Initialization has completed
+    fn finish_init(&mut self) {
+        self.reset_expected = false;
+        self.reset_in_progress = false;
+        self.initialized = true;
     }
 
-    pub fn enable_arvr_stabilised_game_rotation_vector(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(SENSOR_REPORTID_ARVR_STABILISED_GAME_ROTATION_VECTOR, flags, millis_between_reports)
+    /// The sensor hub should announce a reset soon, at our request
+    fn expect_reset(&mut self) {
+        self.reset_expected = true;
+        self.reset_waited_ms = 0;
     }
 
-    pub fn enable_geomagnetic_rotation_vector(
-        &mut self,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        self.enable_report(
-            SENSOR_REPORTID_GEOMAG_VECTOR,
-            flags,
-            millis_between_reports,
-        )
+    /// Account for a poll of up to `max_ms` that found nothing to read:
+    /// a reset not announced within `RESET_TIMEOUT_MS` of such polls
+    /// is no longer expected, so that later resets are noticed
+    fn idle_polled(&mut self, max_ms: u8) {
+        if !self.reset_expected {
+            return;
+        }
+        self.reset_waited_ms =
+            self.reset_waited_ms.saturating_add(max_ms.into());
+        if self.reset_waited_ms >= RESET_TIMEOUT_MS {
+            println!("expected reset never announced");
+            self.reset_expected = false;
+        }
     }
 
-    /// Enable a particular report
-    pub fn enable_report(
-        &mut self,
-        report_id: u8,
-        flags: u8,
-        millis_between_reports: u16,
-    ) -> Result<(), WrapperError<SE>> {
-        println!("enable_report 0x{:X}", report_id);
-
-        let micros_between_reports: u32 =
-            (millis_between_reports as u32) * 1000;
-        let cmd_body: [u8; 17] = [
-            SHUB_REPORT_SET_FEATURE_CMD,
-            report_id,
-            flags,                                    //feature flags
-            0,                                        //LSB change sensitivity
-            0,                                        //MSB change sensitivity
-            (micros_between_reports & 0xFFu32) as u8, // LSB report interval, microseconds
-            (micros_between_reports.shr(8) & 0xFFu32) as u8,
-            (micros_between_reports.shr(16) & 0xFFu32) as u8,
-            (micros_between_reports.shr(24) & 0xFFu32) as u8, // MSB report interval
-            0, // LSB Batch Interval
-            0,
-            0,
-            0, // MSB Batch interval
-            0, // LSB sensor-specific config
-            0,
-            0,
-            0, // MSB sensor-specific config
-        ];
-
-        //we simply blast out this configuration command and assume it'll succeed
-        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body)?;
-        // any error or success in configuration will arrive some time later
+    /// Forget the product ID received earlier, if any,
+    /// returning the request for a new one
+    fn start_product_id_request(&mut self) -> [u8; 2] {
+        self.prod_id_verified = false;
+        self.product_info = ProductInfo::default();
+        PRODUCT_ID_REQUEST
+    }
 
+    /// Check the product ID received since `start_product_id_request`
+    fn check_product_id<SE>(&self) -> Result<(), WrapperError<SE>> {
+        if !self.prod_id_verified {
+            return Err(WrapperError::InvalidChipId(0));
+        }
+        let sw_version_major = self.product_info.records()[0].sw_version_major;
+        if sw_version_major < self.min_fw_version_major {
+            return Err(WrapperError::InvalidFWVersion(sw_version_major));
+        }
         Ok(())
     }
 
-    /// Prepare a packet for sending, in our send buffer
-    fn prep_send_packet(&mut self, channel: u8, body_data: &[u8]) -> usize {
-        let body_len = body_data.len();
-
-        let packet_length = body_len + PACKET_HEADER_LENGTH;
-        let packet_header = [
-            (packet_length & 0xFF) as u8, //LSB
-            packet_length.shr(8) as u8,   //MSB
-            channel,
-            self.sequence_numbers[channel as usize],
-        ];
-        self.sequence_numbers[channel as usize] += 1;
-
-        self.packet_send_buf[..PACKET_HEADER_LENGTH]
-            .copy_from_slice(packet_header.as_ref());
-        self.packet_send_buf[PACKET_HEADER_LENGTH..packet_length]
-            .copy_from_slice(body_data);
+    /// Build the Set Feature command for `config`,
+    /// if the sensor hub is awake to be configured
+    fn prepare_feature<SE>(
+        &self,
+        config: &FeatureConfig,
+    ) -> Result<[u8; 17], WrapperError<SE>> {
+        println!("enable_report 0x{:X}", config.report_id);
+        self.check_awake()?;
+        Ok(Self::set_feature_command(config))
+    }
 
-        packet_length
+    /// Take the next request decided on while handling received packets
+    fn take_deferred_request(&mut self) -> Option<DeferredRequest> {
+        if core::mem::take(&mut self.product_id_request_pending) {
+            Some(DeferredRequest::ProductId)
+        } else if core::mem::take(&mut self.reapply_pending) {
+            Some(DeferredRequest::ReapplyFeatures)
+        } else {
+            None
+        }
     }
 
-    /// Send packet from our packet send buf
-    fn send_packet(
+    /// Build an SH-2 command request with up to nine parameters,
+    /// taking the next command sequence number
+    fn command_request<SE>(
         &mut self,
-        channel: u8,
-        body_data: &[u8],
-    ) -> Result<usize, WrapperError<SE>> {
-        let packet_length = self.prep_send_packet(channel, body_data);
-        self.sensor_interface
-            .write_packet(&self.packet_send_buf[..packet_length])
-            .map_err(WrapperError::CommError)?;
-        Ok(packet_length)
+        command: u8,
+        params: &[u8],
+    ) -> Result<[u8; SHUB_COMMAND_REQ_LENGTH], WrapperError<SE>> {
+        if params.len() > SHUB_COMMAND_REQ_LENGTH - 3 {
+            return Err(WrapperError::PacketTooLarge(
+                PACKET_HEADER_LENGTH + 3 + params.len(),
+            ));
+        }
+        let mut cmd_body = [0u8; SHUB_COMMAND_REQ_LENGTH];
+        cmd_body[0] = SHUB_COMMAND_REQ;
+        cmd_body[1] = self.command_sequence;
+        cmd_body[2] = command;
+        cmd_body[3..3 + params.len()].copy_from_slice(params);
+        self.command_sequence = self.command_sequence.wrapping_add(1);
+        Ok(cmd_body)
     }
 
-    /// Read one packet into the receive buffer
-    pub(crate) fn receive_packet_with_timeout(
+    /// Start collecting up to `response_count` responses
+    /// to the command request `cmd_body`, just sent
+    fn start_command_wait(
         &mut self,
-        delay: &mut impl DelayNs,
-        max_ms: u8,
-    ) -> Result<usize, WrapperError<SE>> {
-        //
-        // println!("r_p");
-
-        self.packet_recv_buf[0] = 0;
-        self.packet_recv_buf[1] = 0;
-        let packet_len = self
-            .sensor_interface
-            .read_with_timeout(&mut self.packet_recv_buf, delay, max_ms)
-            .map_err(WrapperError::CommError)?;
-
-        self.last_packet_len_received = packet_len;
-        //
-        // println!("recv {}", packet_len);
+        cmd_body: &[u8; SHUB_COMMAND_REQ_LENGTH],
+        response_count: usize,
+    ) -> CommandWait {
+        let command = cmd_body[2];
+        self.command_response_count = 0;
+        self.pending_command = Some((command, cmd_body[1]));
+        CommandWait {
+            command,
+            response_count: response_count.min(MAX_COMMAND_RESPONSES),
+            timeout_ms: command_timeout_ms(command),
+            waited_ms: 0,
+        }
+    }
 
-        Ok(packet_len)
+    /// Have all the responses awaited arrived?
+    /// Fails once the command's timeout has passed without them.
+    fn command_complete<SE>(
+        &mut self,
+        wait: &CommandWait,
+    ) -> Result<bool, WrapperError<SE>> {
+        if self.command_response_count >= wait.response_count {
+            self.pending_command = None;
+            return Ok(true);
+        }
+        if wait.waited_ms >= wait.timeout_ms {
+            self.pending_command = None;
+            increment(&mut self.stats.timeouts);
+            return Err(WrapperError::CommandTimeout(wait.command));
+        }
+        Ok(false)
     }
 
-    /// Verify that the sensor returns an expected chip ID
-    fn verify_product_id(
+    /// Account for one poll for command responses, which returned `rc`:
+    /// only a poll that found nothing to read counts towards the timeout
+    fn command_polled<SE>(
         &mut self,
-        delay: &mut impl DelayNs,
+        wait: &mut CommandWait,
+        rc: Result<u32, WrapperError<SE>>,
     ) -> Result<(), WrapperError<SE>> {
-        println!("request PID...");
-        let cmd_body: [u8; 2] = [
-            SHUB_PROD_ID_REQ, //request product ID
-            0,                //reserved
-        ];
-
-        // for some reason, reading PID right sending request does not work with i2c
-        if self.sensor_interface.requires_soft_reset() {
-            self.send_packet(CHANNEL_HUB_CONTROL, cmd_body.as_ref())?;
-        } else {
-            let response_size = self.send_and_receive_packet(
-                CHANNEL_HUB_CONTROL,
-                cmd_body.as_ref(),
-            )?;
-            if response_size > 0 {
-                self.handle_received_packet(response_size);
-            }
-        };
-
-        // process all incoming messages until we get a product id (or no more data)
-        while !self.prod_id_verified {
-            static mut ITERATIONS: u8 = 0;
-            println!("read PID");
-            #[cfg_attr(not(feature = "defmt-03"), allow(unused))]
-            unsafe {
-                println!("Tried reading PID {} times", ITERATIONS);
+        match rc {
+            Ok(0) => wait.waited_ms += COMMAND_POLL_MS as u16,
+            Ok(_) => {}
+            Err(e) => {
+                self.pending_command = None;
+                return Err(e);
             }
-            let msg_count = self.handle_one_message(delay, 150u8);
-            if msg_count < 1 {
-                break;
-            }
-            unsafe { ITERATIONS += 1 }
         }
+        Ok(())
+    }
 
-        if !self.prod_id_verified {
-            return Err(WrapperError::InvalidChipId(0));
+    /// Responses collected for the most recent command request
+    fn command_responses(&self) -> &[CommandResponse] {
+        &self.command_responses[..self.command_response_count]
+    }
+
+    /// Check the status in the response to the initialize command
+    fn check_initialize_status<SE>(
+        responses: &[CommandResponse],
+    ) -> Result<(), WrapperError<SE>> {
+        // R0 holds the status: zero for success
+        let status = responses[0].values[0];
+        if status != 0 {
+            return Err(WrapperError::InitializeFailed(status));
         }
         Ok(())
     }
 
-    /// Read normalized quaternion:
-    /// QX normalized quaternion – X, or Heading | range: 0.0 – 1.0 ( ±π )
-    /// QY normalized quaternion – Y, or Pitch   | range: 0.0 – 1.0 ( ±π/2 )
-    /// QZ normalized quaternion – Z, or Roll    | range: 0.0 – 1.0 ( ±π )
-    /// QW normalized quaternion – W, or 0.0     | range: 0.0 – 1.0
-    pub fn rotation_quaternion(&self) -> Result<[f32; 4], WrapperError<SE>> {
-        Ok(self.rotation_quaternion)
+    /// Configuration of every feature currently enabled
+    pub fn enabled_features(&self) -> impl Iterator<Item = &FeatureConfig> {
+        self.enabled_features.iter().flatten()
     }
 
     pub fn heading_accuracy(&self) -> f32 {
         self.rot_quaternion_acc
     }
 
-    /// Read linear acceleration (m/s^2)
-    pub fn linear_accel(&self) -> Result<[f32; 3], WrapperError<SE>> {
-        Ok(self.linear_accel)
+    /// Product ID records most recently reported by the sensor hub
+    pub fn product_info(&self) -> &ProductInfo {
+        &self.product_info
     }
 
-    /// Read gyroscope data (rad/s)
-    pub fn gyro(&self) -> Result<[f32; 3], WrapperError<SE>> {
-        Ok(self.gyro)
+    /// SHTP errors in the error list most recently sent by the sensor hub
+    pub fn error_list(&self) -> &[ShtpError] {
+        &self.error_list[..self.error_list_len]
     }
 
-    /// Tell the sensor to reset.
-    /// Normally applications should not need to call this directly,
-    /// as it is called during `init`.
-    pub fn soft_reset(&mut self) -> Result<(), WrapperError<SE>> {
-        //
-        // println!("soft_reset");
-        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_RESET];
-        // send command packet and ignore received packets
-        let received_len =
-            self.send_and_receive_packet(CHANNEL_EXECUTABLE, data.as_ref())?;
-        if received_len > 0 {
-            self.handle_received_packet(received_len);
-        }
+    /// Number of packets missing from the sequence received on `channel`,
+    /// for instance because they were not read quickly enough
+    pub fn dropped_packets(&self, channel: u8) -> u32 {
+        self.stats
+            .dropped_packets
+            .get(channel as usize)
+            .copied()
+            .unwrap_or(0)
+    }
 
-        Ok(())
+    /// Number of input reports with `report_id` missing from the sequence
+    /// received: a sign that reports are produced faster than we read them
+    pub fn dropped_reports(&self, report_id: u8) -> u32 {
+        self.dropped_reports
+            .get(report_id as usize)
+            .copied()
+            .unwrap_or(0)
     }
 
-    /// Send a packet and receive the response
-    fn send_and_receive_packet(
-        &mut self,
-        channel: u8,
-        body_data: &[u8],
-    ) -> Result<usize, WrapperError<SE>> {
-        let send_packet_length = self.prep_send_packet(channel, body_data);
-        //
-        // println!("srcv {} ...", send_packet_length);
+    /// Link health counters accumulated since the last `reset_stats`
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
 
-        let recv_packet_length = self
-            .sensor_interface
-            .send_and_receive_packet(
-                &self.packet_send_buf[..send_packet_length].as_ref(),
-                &mut self.packet_recv_buf,
-            )
-            .map_err(WrapperError::CommError)?;
+    /// Reset every link health counter, including dropped report counts,
+    /// to zero
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.dropped_reports = [0; NUM_TRACKED_REPORT_IDS];
+    }
 
-        println!("srcv {} {}", send_packet_length, recv_packet_length);
+    /// Take the most recent event noticed while handling messages, if any
+    pub fn take_event(&mut self) -> Option<Event> {
+        self.pending_event.take()
+    }
 
-        Ok(recv_packet_length)
+    /// Choose whether every enabled feature is configured again
+    /// after the sensor hub resets unexpectedly
+    pub fn set_reapply_on_reset(&mut self, reapply: bool) {
+        self.reapply_on_reset = reapply;
+    }
+
+    /// Make `init` fail with `WrapperError::InvalidFWVersion` when the
+    /// sensor hub firmware major version is older than `major`.
+    /// By default, any firmware version is accepted.
+    pub fn set_min_fw_version_major(&mut self, major: u8) {
+        self.min_fw_version_major = major;
+    }
+
+    /// Power state most recently requested of the sensor hub
+    pub fn power_state(&self) -> PowerState {
+        self.power_state
+    }
+
+    /// Count an error from the sensor interface before returning it
+    fn count_comm_error<T, SE>(
+        &mut self,
+        rc: Result<T, SE>,
+    ) -> Result<T, WrapperError<SE>> {
+        rc.map_err(|e| {
+            increment(&mut self.stats.comm_errors);
+            WrapperError::CommError(e)
+        })
+    }
+
+    /// Count a packet of `packet_len` bytes just read into the receive buffer
+    fn count_packet_received(&mut self, packet_len: usize) {
+        if packet_len == 0 {
+            return;
+        }
+        self.stats.bytes_received =
+            self.stats.bytes_received.saturating_add(packet_len as u32);
+        let channel = self.packet_recv_buf[2] as usize;
+        if let Some(count) = self.stats.packets_received.get_mut(channel) {
+            increment(count);
+        }
     }
 }
 
+/// Add one to a statistics counter, saturating rather than overflowing
+fn increment(counter: &mut u32) {
+    *counter = counter.saturating_add(1);
+}
+
 const Q8_SCALE: f32 = 1.0 / ((1 << 8) as f32);
 const Q9_SCALE: f32 = 1.0 / ((1 << 9) as f32);
 const Q12_SCALE: f32 = 1.0 / ((1 << 12) as f32);
@@ -781,39 +2188,73 @@ fn q9_to_f32(q_val: i16) -> f32 {
 }
 
 // The BNO080 supports six communication channels:
-const CHANNEL_COMMAND: u8 = 0;
 /// the SHTP command channel
-const CHANNEL_EXECUTABLE: u8 = 1;
+const CHANNEL_COMMAND: u8 = 0;
 /// executable channel
-const CHANNEL_HUB_CONTROL: u8 = 2;
+const CHANNEL_EXECUTABLE: u8 = 1;
 /// sensor hub control channel
-const CHANNEL_SENSOR_REPORTS: u8 = 3;
+const CHANNEL_HUB_CONTROL: u8 = 2;
 /// input sensor reports (non-wake, not gyroRV)
-//const  CHANNEL_WAKE_REPORTS: usize = 4; /// wake input sensor reports (for sensors configured as wake up sensors)
+const CHANNEL_SENSOR_REPORTS: u8 = 3;
+/// wake input sensor reports (for sensors configured as wake up sensors)
+const CHANNEL_WAKE_REPORTS: u8 = 4;
 //const  CHANNEL_GYRO_ROTATION: usize = 5; ///  gyro rotation vector (gyroRV)
 
-/// Command Channel requests / responses
+// Command Channel requests / responses
 
 // Commands
-//const CMD_GET_ADVERTISEMENT: u8 = 0;
-//const CMD_SEND_ERROR_LIST: u8 = 1;
+const CMD_GET_ADVERTISEMENT: u8 = 0;
+const CMD_SEND_ERROR_LIST: u8 = 1;
+/// Parameter asking for the advertisement of every application
+const ADVERTISE_ALL: u8 = 1;
 
-/// Responses
+// Responses
 const CMD_RESP_ADVERTISEMENT: u8 = 0;
 const CMD_RESP_ERROR_LIST: u8 = 1;
 
-/// SHTP constants
+// SHTP constants
 
 /// Report ID for Product ID request
 const SHUB_PROD_ID_REQ: u8 = 0xF9;
+/// Product ID request: report ID, then a reserved byte
+const PRODUCT_ID_REQUEST: [u8; 2] = [SHUB_PROD_ID_REQ, 0];
 /// Report ID for Product ID response
 const SHUB_PROD_ID_RESP: u8 = 0xF8;
+/// Length of each record in a Product ID response
+const PROD_ID_RECORD_LENGTH: usize = 16;
 const SHUB_GET_FEATURE_RESP: u8 = 0xFC;
 const SHUB_REPORT_SET_FEATURE_CMD: u8 = 0xFD;
 // const SHUB_GET_FEATURE_REQ: u8 = 0xFE;
-// const SHUB_FORCE_SENSOR_FLUSH: u8 = 0xF0;
+/// Report ID for Force Sensor Flush request
+const SHUB_FORCE_SENSOR_FLUSH: u8 = 0xF0;
+/// Report ID for Flush Completed response
+const SHUB_FLUSH_COMPLETED: u8 = 0xEF;
+/// Base timestamp reference that starts each input report packet
+const SHUB_BASE_TIMESTAMP_REF: u8 = 0xFB;
+/// Timestamp rebase, found in batched input report packets
+const SHUB_TIMESTAMP_REBASE: u8 = 0xFA;
 const SHUB_COMMAND_RESP: u8 = 0xF1;
-//const SHUB_COMMAND_REQ:u8 =  0xF2;
+/// Length of a command response record
+const SHUB_COMMAND_RESP_LENGTH: usize = 16;
+/// Report ID for Command Request
+const SHUB_COMMAND_REQ: u8 = 0xF2;
+/// Length of a command request record
+const SHUB_COMMAND_REQ_LENGTH: usize = 12;
+/// How long to wait for each message while awaiting a command response
+const COMMAND_POLL_MS: u8 = 10;
+/// How long to wait for the sensor hub to announce a reset we asked for
+const RESET_TIMEOUT_MS: u16 = 1000;
+/// How long to wait for the responses to most commands
+const DEFAULT_COMMAND_TIMEOUT_MS: u16 = 300;
+
+/// Maximum time in milliseconds to wait for the responses to `command`
+fn command_timeout_ms(command: u8) -> u16 {
+    match command {
+        // reinitializing restarts the sensor hub's sensors
+        SH2_CMD_INITIALIZE => 1000,
+        _ => DEFAULT_COMMAND_TIMEOUT_MS,
+    }
+}
 
 // some mysterious responses we sometimes get:
 // 0x78, 0x7C
@@ -842,12 +2283,29 @@ const SENSOR_REPORTID_GEOMAG_VECTOR: u8 = 0x09;
 const SENSOR_REPORTID_ARVR_STABILISED_ROTATION_VECTOR: u8 = 0x28;
 const SENSOR_REPORTID_ARVR_STABILISED_GAME_ROTATION_VECTOR: u8 = 0x29;
 
+/// Length in bytes of each record found on the input report channels,
+/// as advertised by the sensor hub
+fn sensor_report_length(report_id: u8) -> Option<usize> {
+    let len = match report_id {
+        SHUB_BASE_TIMESTAMP_REF | SHUB_TIMESTAMP_REBASE => 5,
+        0x10 => 5,
+        0x0C..=0x0E | 0x12 | 0x13 | 0x19 | 0x1C => 6,
+        0x0A | 0x0B | 0x18 => 8,
+        0x01..=0x04 | 0x06 => 10,
+        0x08 | 0x11 | 0x29 => 12,
+        0x05 | 0x09 | 0x28 | 0x2A => 14,
+        0x07 | 0x0F | 0x14..=0x16 | 0x1E => 16,
+        _ => return None,
+    };
+    Some(len)
+}
+
 /// executable/device channel responses
 /// Figure 1-27: SHTP executable commands and response
 // const EXECUTABLE_DEVICE_CMD_UNKNOWN: u8 =  0;
 const EXECUTABLE_DEVICE_CMD_RESET: u8 = 1;
-//const EXECUTABLE_DEVICE_CMD_ON: u8 =   2;
-//const EXECUTABLE_DEVICE_CMD_SLEEP =  3;
+const EXECUTABLE_DEVICE_CMD_ON: u8 = 2;
+const EXECUTABLE_DEVICE_CMD_SLEEP: u8 = 3;
 
 /// Response to CMD_RESET
 const EXECUTABLE_DEVICE_RESP_RESET_COMPLETE: u8 = 1;
@@ -856,8 +2314,15 @@ const EXECUTABLE_DEVICE_RESP_RESET_COMPLETE: u8 = 1;
 const SH2_INIT_UNSOLICITED: u8 = 0x80;
 const SH2_CMD_INITIALIZE: u8 = 4;
 const SH2_INIT_SYSTEM: u8 = 1;
+const SH2_INIT_SENSOR_HUB: u8 = 2;
 const SH2_STARTUP_INIT_UNSOLICITED: u8 =
     SH2_CMD_INITIALIZE | SH2_INIT_UNSOLICITED;
+/// Counter commands, for per-sensor statistics
+const SH2_CMD_COUNTS: u8 = 2;
+const SH2_COUNTS_GET: u8 = 0;
+const SH2_COUNTS_CLEAR: u8 = 1;
+/// Get oscillator type command
+const SH2_CMD_GET_OSC_TYPE: u8 = 0x0A;
 
 // let cmd_body: [u8; 17] = [
 //     SHUB_REPORT_SET_FEATURE_CMD,
@@ -882,12 +2347,21 @@ const SH2_STARTUP_INIT_UNSOLICITED: u8 =
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
+    extern crate std;
+
     // use super::*;
     use crate::interface::i2c::DEFAULT_ADDRESS;
     // use crate::interface::mock_i2c_port::FakeI2cPort;
-    use crate::wrapper::{q14_to_f32, BNO080, Q14_SCALE};
+    use crate::interface::mock_interface::{FakeDelay, FakeSensorInterface};
+    use crate::wrapper::{
+        q14_to_f32, Event, FeatureConfig, InitSubsystem, OscillatorType,
+        PowerState, ProductIdRecord, ResetCause, SensorCounts, ShtpError,
+        Stats, WrapperError, BNO080, MAX_ENABLED_FEATURES, Q14_SCALE,
+        RESET_TIMEOUT_MS,
+    };
 
     use crate::interface::I2cInterface;
+    use std::vec::Vec;
 
     fn f32_to_q14(input: f32) -> i16 {
         (input / Q14_SCALE) as i16
@@ -900,6 +2374,688 @@ mod tests {
         assert_eq!(float_val, 0.5);
     }
 
+    #[test]
+    fn test_flush() {
+        let mut fake = FakeSensorInterface::new();
+        // a batched rotation vector report, then the flush completed response
+        fake.add_available_packet(&[
+            23, 0, 3, 0, 0xFB, 0, 0, 0, 0, 0x05, 1, 2, 3, 0, 0, 0, 0, 0, 0,
+            0x00, 0x40, 0, 0,
+        ]);
+        fake.add_available_packet(&[6, 0, 2, 0, 0xEF, 0x05]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let rc = shub.flush(0x05, &mut FakeDelay {});
+        assert!(rc.is_ok());
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets.len(), 1);
+        assert_eq!(fake.sent_packets[0], [6, 0, 2, 0, 0xF0, 0x05]);
+    }
+
+    #[test]
+    fn test_drain_batched_reports() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            42, 0, 3, 0, // packet header
+            0xFB, 100, 0, 0, 0, // base timestamp 10 ms before interrupt
+            0x05, 1, 0x03, 0, // rotation vector, no delay
+            0, 0, 0, 0, 0, 0, 0x00, 0x40, 0, 0, // values
+            0xFA, 20, 0, 0, 0, // rebase 2 ms later
+            0x05, 2, 0x03, 5, // rotation vector, 0.5 ms delay
+            0x00, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, // values
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let mut samples = [None; 4];
+        let mut sample_count = 0;
+        let packet_count =
+            shub.drain_batched_reports(&mut FakeDelay {}, 1, |sample| {
+                samples[sample_count] = Some(*sample);
+                sample_count += 1;
+            });
+        assert_eq!(packet_count.ok(), Some(1));
+        assert_eq!(sample_count, 2);
+
+        let first = samples[0].unwrap();
+        assert_eq!(first.sequence, 1);
+        assert_eq!(first.status, 3);
+        assert_eq!(first.timestamp_us, -10_000);
+        let second = samples[1].unwrap();
+        assert_eq!(second.sequence, 2);
+        assert_eq!(second.timestamp_us, -7_500);
+
+        // the most recent sample wins
+        let quat = shub.rotation_quaternion().unwrap();
+        assert_eq!(quat, [1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_truncated_batch_keeps_whole_reports() {
+        let mut fake = FakeSensorInterface::new();
+        // header promises more reports than were received
+        fake.add_available_packet(&[
+            0x00, 0x02, 3, 0, // packet header
+            0xFB, 0, 0, 0, 0, // base timestamp
+            0x05, 1, 0x03, 0, // rotation vector
+            0x00, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, // values
+            0x05, 2, 0x03, 0, 0, 0, // partial report
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let mut sample_count = 0;
+        shub.drain_batched_reports(&mut FakeDelay {}, 1, |_| {
+            sample_count += 1;
+        });
+        assert_eq!(sample_count, 1);
+    }
+
+    #[test]
+    fn test_enable_batched_report() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        let rc = shub.enable_batched_report(0x05, 0, 2_500, 100_000);
+        assert!(rc.is_ok());
+
+        let fake = shub.free();
+        let sent = &fake.sent_packets[0];
+        assert_eq!(sent[4], 0xFD);
+        assert_eq!(sent[5], 0x05);
+        assert_eq!(sent[9..13], 2_500u32.to_le_bytes());
+        assert_eq!(sent[13..17], 100_000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_product_info() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            36, 0, 2, 0, // packet header
+            0xF8, 0x01, 3, 2, // power on reset, version 3.2
+            0x96, 0xA4, 0x98, 0x00, 7, 0, 0, 0, 1, 0, 0, 0, // part, build
+            0xF8, 0x00, 3, 1, // version 3.1
+            0xE3, 0x9F, 0x98, 0, 0x17, 0, 0, 0, 0x34, 0x92, 0, 0, // patch
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        assert!(shub.verify_product_id(&mut FakeDelay {}).is_ok());
+
+        let info = shub.product_info();
+        assert_eq!(info.records().len(), 2);
+        assert_eq!(info.reset_cause(), ResetCause::PowerOn);
+        assert_eq!(
+            info.records()[0],
+            ProductIdRecord {
+                reset_cause: ResetCause::PowerOn,
+                sw_version_major: 3,
+                sw_version_minor: 2,
+                sw_part_number: 10003606,
+                sw_build_number: 7,
+                sw_version_patch: 1,
+            }
+        );
+        assert_eq!(info.records()[1].sw_part_number, 10002403);
+        assert_eq!(info.records()[1].sw_build_number, 0x17);
+        assert_eq!(info.records()[1].sw_version_patch, 0x9234);
+    }
+
+    #[test]
+    fn test_unsupported_firmware() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF8, 0x00, 2, 0, // version 2.0
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // part, build
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake.clone());
+        assert!(shub.verify_product_id(&mut FakeDelay {}).is_ok());
+
+        let mut shub = BNO080::new_with_interface(fake);
+        shub.set_min_fw_version_major(3);
+        let rc = shub.verify_product_id(&mut FakeDelay {});
+        assert!(matches!(rc, Err(WrapperError::InvalidFWVersion(2))));
+    }
+
+    #[test]
+    fn test_unexpected_reset() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        shub.initialized = true;
+        shub.set_reapply_on_reset(true);
+        assert!(shub.enable_rotation_vector(0, 10).is_ok());
+
+        // reset complete, unsolicited initialize response, then product ID
+        shub.sensor_interface.add_available_packet(&[5, 0, 1, 0, 1]);
+        shub.sensor_interface.add_available_packet(&[
+            20, 0, 2, 0, 0xF1, 0, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        shub.sensor_interface.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF8, 0x03, 3, 2, // watchdog reset, version 3.2
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // part, build
+        ]);
+        let handled = shub.handle_all_messages(&mut FakeDelay {}, 1);
+        assert_eq!(handled.ok(), Some(3));
+
+        assert_eq!(shub.take_event(), Some(Event::Reset(ResetCause::Watchdog)));
+        assert_eq!(shub.take_event(), None);
+
+        let fake = shub.free();
+        // enable, product ID request, then enable again
+        assert_eq!(fake.sent_packets.len(), 3);
+        assert_eq!(fake.sent_packets[1][4], 0xF9);
+        assert_eq!(fake.sent_packets[2][4..6], [0xFD, 0x05]);
+    }
+
+    #[test]
+    fn test_reset_without_product_id() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        shub.initialized = true;
+        shub.set_reapply_on_reset(true);
+        assert!(shub.enable_rotation_vector(0, 10).is_ok());
+
+        // the product ID requested after each reset never arrives
+        for _ in 0..2 {
+            shub.sensor_interface.add_available_packet(&[5, 0, 1, 0, 1]);
+            shub.sensor_interface.add_available_packet(&[
+                20, 0, 2, 0, 0xF1, 0, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0,
+            ]);
+            let handled = shub.handle_all_messages(&mut FakeDelay {}, 1);
+            assert_eq!(handled.ok(), Some(2));
+            assert_eq!(
+                shub.take_event(),
+                Some(Event::Reset(ResetCause::NotApplicable))
+            );
+        }
+
+        let fake = shub.free();
+        // enable, then product ID request and enable again per reset
+        assert_eq!(fake.sent_packets.len(), 5);
+        assert_eq!(fake.sent_packets[3][4], 0xF9);
+        assert_eq!(fake.sent_packets[4][4..6], [0xFD, 0x05]);
+    }
+
+    #[test]
+    fn test_expected_reset_never_announced() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        shub.initialized = true;
+        assert!(shub.soft_reset().is_ok());
+
+        // poll long enough for the reset we asked for to be forgotten
+        for _ in 0..(RESET_TIMEOUT_MS / 100) {
+            let handled = shub.handle_one_message(&mut FakeDelay {}, 100);
+            assert_eq!(handled.ok(), Some(0));
+        }
+
+        shub.sensor_interface.add_available_packet(&[5, 0, 1, 0, 1]);
+        let handled = shub.handle_all_messages(&mut FakeDelay {}, 1);
+        assert_eq!(handled.ok(), Some(1));
+        assert_eq!(
+            shub.take_event(),
+            Some(Event::Reset(ResetCause::NotApplicable))
+        );
+    }
+
+    #[test]
+    fn test_expected_reset() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        shub.initialized = true;
+        assert!(shub.soft_reset().is_ok());
+
+        shub.sensor_interface.add_available_packet(&[5, 0, 1, 0, 1]);
+        shub.sensor_interface.add_available_packet(&[
+            20, 0, 2, 0, 0xF1, 0, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        let handled = shub.handle_all_messages(&mut FakeDelay {}, 1);
+        assert_eq!(handled.ok(), Some(2));
+        assert_eq!(shub.take_event(), None);
+    }
+
+    #[test]
+    fn test_feature_table() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        let config = FeatureConfig {
+            report_id: 0x05,
+            flags: 0x01,
+            change_sensitivity: 0x0102,
+            micros_between_reports: 2_500,
+            batch_interval_us: 100_000,
+            sensor_specific_config: 0x0A0B0C0D,
+        };
+        assert!(shub.enable_feature(&config).is_ok());
+        // reconfiguring a feature replaces its entry
+        assert!(shub.enable_feature(&config).is_ok());
+        for report_id in 1..MAX_ENABLED_FEATURES as u8 {
+            assert!(shub.enable_report(0x10 + report_id, 0, 10).is_ok());
+        }
+        assert_eq!(shub.enabled_features().count(), MAX_ENABLED_FEATURES);
+        // once the table is full, features are still configured
+        assert!(shub.enable_report(0x30, 0, 10).is_ok());
+        assert_eq!(shub.enabled_features().count(), MAX_ENABLED_FEATURES);
+        assert_eq!(shub.stats().unremembered_features, 1);
+        assert_eq!(
+            shub.sensor_interface.sent_packets.last().unwrap()[4..6],
+            [0xFD, 0x30]
+        );
+
+        // disabling a feature frees its entry
+        assert!(shub.disable_report(0x05).is_ok());
+        assert!(shub.enable_report(0x30, 0, 10).is_ok());
+        assert!(shub.disable_report(0x31).is_ok());
+
+        shub.sensor_interface.sent_packets.clear();
+        assert!(shub.reapply_features().is_ok());
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets.len(), MAX_ENABLED_FEATURES);
+    }
+
+    #[test]
+    fn test_feature_config_encoding() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        let config = FeatureConfig {
+            report_id: 0x05,
+            flags: 0x01,
+            change_sensitivity: 0x0102,
+            micros_between_reports: 2_500,
+            batch_interval_us: 100_000,
+            sensor_specific_config: 0x0A0B0C0D,
+        };
+        assert!(shub.enable_feature(&config).is_ok());
+        assert_eq!(shub.enabled_features().next(), Some(&config));
+
+        let fake = shub.free();
+        let sent = &fake.sent_packets[0];
+        assert_eq!(sent[4..9], [0xFD, 0x05, 0x01, 0x02, 0x01]);
+        assert_eq!(sent[9..13], 2_500u32.to_le_bytes());
+        assert_eq!(sent[13..17], 100_000u32.to_le_bytes());
+        assert_eq!(sent[17..21], [0x0D, 0x0C, 0x0B, 0x0A]);
+    }
+
+    #[test]
+    fn test_sleep_and_wake() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        assert_eq!(shub.power_state(), PowerState::On);
+
+        assert!(shub.sleep().is_ok());
+        assert_eq!(shub.power_state(), PowerState::Sleep);
+        assert!(matches!(
+            shub.enable_rotation_vector(0, 10),
+            Err(WrapperError::SensorAsleep)
+        ));
+
+        assert!(shub.wake().is_ok());
+        assert_eq!(shub.power_state(), PowerState::On);
+        assert!(shub.enable_rotation_vector(0, 10).is_ok());
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets.len(), 3);
+        assert_eq!(fake.sent_packets[0], [5, 0, 1, 0, 3]);
+        assert_eq!(fake.sent_packets[1], [5, 0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_request_error_list() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[8, 0, 0, 0, 0x01, 3, 9, 0x42]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        assert!(shub.request_error_list(&mut FakeDelay {}).is_ok());
+        assert_eq!(
+            shub.error_list(),
+            [
+                ShtpError::WriteCargoTooLong,
+                ShtpError::UnknownChannel,
+                ShtpError::Other(0x42)
+            ]
+        );
+
+        // a requested error list is not an event
+        assert_eq!(shub.take_event(), None);
+        assert_eq!(shub.stats().hub_errors, 0);
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets[0], [5, 0, 0, 0, 0x01]);
+    }
+
+    #[test]
+    fn test_request_advertisement() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&ADVERTISING_PACKET_FULL);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        assert!(shub.request_advertisement(&mut FakeDelay {}).is_ok());
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets[0], [6, 0, 0, 0, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_get_counts() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF1, 0, 0x02, 0, 0, // counts response part 0
+            0x00, 0x05, 0, // subcommand, sensor ID, reserved
+            0x10, 0x27, 0, 0, // offered
+            0x0F, 0x27, 0, 0, // accepted
+        ]);
+        fake.add_available_packet(&[
+            20, 0, 2, 1, // packet header
+            0xF1, 1, 0x02, 0, 1, // counts response part 1
+            0x00, 0x05, 0, // subcommand, sensor ID, reserved
+            0x08, 0x27, 0, 0, // on
+            0x07, 0x27, 0, 0, // attempted
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let counts = shub.get_counts(0x05, &mut FakeDelay {}).unwrap();
+        assert_eq!(
+            counts,
+            SensorCounts {
+                offered: 10_000,
+                accepted: 9_999,
+                on: 9_992,
+                attempted: 9_991,
+            }
+        );
+        assert!(shub.clear_counts(0x05).is_ok());
+
+        let fake = shub.free();
+        assert_eq!(
+            fake.sent_packets[0],
+            [16, 0, 2, 0, 0xF2, 0, 0x02, 0x00, 0x05, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(fake.sent_packets[1][4..9], [0xF2, 1, 0x02, 0x01, 0x05]);
+        // nothing answers the clear request to carry it along
+        assert_eq!(fake.flushes, 1);
+    }
+
+    #[test]
+    fn test_oscillator_type() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF1, 0, 0x0A, 0, 0, // oscillator type response
+            0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let osc_type = shub.oscillator_type(&mut FakeDelay {});
+        assert_eq!(osc_type.ok(), Some(OscillatorType::ExternalCrystal));
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets[0][4..7], [0xF2, 0, 0x0A]);
+    }
+
+    #[test]
+    fn test_command_response_matching() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF1, 0, 0x0A, 7, 0, // response to a stale request
+            0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        fake.add_available_packet(&[
+            20, 0, 2, 1, // packet header
+            0xF1, 1, 0x0A, 0, 0, // response to our request
+            0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let osc_type = shub.oscillator_type(&mut FakeDelay {});
+        assert_eq!(osc_type.ok(), Some(OscillatorType::Internal));
+    }
+
+    #[test]
+    fn test_command_response_among_reports() {
+        let mut fake = FakeSensorInterface::new();
+        // more input reports than polls fit in the command timeout
+        for seq in 0..100u8 {
+            fake.add_available_packet(&[
+                23, 0, 3, seq, 0xFB, 0, 0, 0, 0, 0x05, seq, 2, 3, 0, 0, 0, 0,
+                0, 0, 0x00, 0x40, 0, 0,
+            ]);
+        }
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF1, 0, 0x0A, 0, 0, // response to our request
+            0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let osc_type = shub.oscillator_type(&mut FakeDelay {});
+        assert_eq!(osc_type.ok(), Some(OscillatorType::ExternalCrystal));
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        let rc = shub.get_counts(0x05, &mut FakeDelay {});
+        assert!(matches!(rc, Err(WrapperError::CommandTimeout(0x02))));
+
+        // the next request uses a new command sequence number
+        let _ = shub.oscillator_type(&mut FakeDelay {});
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets[1][4..7], [0xF2, 1, 0x0A]);
+    }
+
+    #[test]
+    fn test_reinitialize() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF1, 0, 0x04, 0, 0, // initialize response: success
+            0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        fake.add_available_packet(&[
+            20, 0, 2, 1, // packet header
+            0xF1, 1, 0x04, 1, 0, // initialize response: failure
+            0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let mut delay = FakeDelay {};
+        assert!(shub
+            .reinitialize(InitSubsystem::SensorHub, &mut delay)
+            .is_ok());
+        let rc = shub.reinitialize(InitSubsystem::EntireSystem, &mut delay);
+        assert!(matches!(rc, Err(WrapperError::InitializeFailed(3))));
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets[0][4..8], [0xF2, 0, 0x04, 0x02]);
+        assert_eq!(fake.sent_packets[1][4..8], [0xF2, 1, 0x04, 0x01]);
+    }
+
+    #[test]
+    fn test_receive_errors() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[3, 0, 2]); // shorter than a header
+        fake.add_available_packet(&[5, 0, 9, 0, 0x01]); // unknown channel
+        fake.add_available_packet(&[
+            8, 0, 2, 0, // packet header
+            0xF1, 0, 0x02, 0, // truncated command response
+        ]);
+        fake.add_available_packet(&[6, 0, 0, 0, 0x01, 0x02]); // error list
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let mut delay = FakeDelay {};
+        let rc = shub.handle_one_message(&mut delay, 1);
+        assert!(matches!(rc, Err(WrapperError::MalformedPacket)));
+        let rc = shub.handle_one_message(&mut delay, 1);
+        assert!(matches!(rc, Err(WrapperError::UnknownChannel(9))));
+        let rc = shub.handle_one_message(&mut delay, 1);
+        assert!(matches!(rc, Err(WrapperError::TruncatedReport(0xF1))));
+        // an error list we did not ask for is not an error of ours
+        assert_eq!(shub.handle_one_message(&mut delay, 1).ok(), Some(1));
+        assert_eq!(
+            shub.take_event(),
+            Some(Event::HubError(ShtpError::WriteTooShort))
+        );
+        assert_eq!(shub.error_list(), [ShtpError::WriteTooShort]);
+        assert_eq!(shub.stats().hub_errors, 1);
+        assert_eq!(shub.handle_one_message(&mut delay, 1).ok(), Some(0));
+    }
+
+    #[test]
+    fn test_oversized_packet() {
+        let mut oversized = [0u8; 24];
+        oversized[..5].copy_from_slice(&[24, 0, 0, 0, 0x01]);
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&oversized);
+        fake.add_available_packet(&[6, 0, 0, 1, 0x01, 0x02]); // error list
+
+        let mut shub = BNO080::<_, 32, 16>::new_with_buffers(fake);
+        let mut delay = FakeDelay {};
+        let rc = shub.handle_one_message(&mut delay, 1);
+        assert!(matches!(rc, Err(WrapperError::OversizedPacket(24))));
+        assert_eq!(shub.handle_one_message(&mut delay, 1).ok(), Some(1));
+
+        let stats = shub.stats();
+        assert_eq!(stats.oversized_packets, 1);
+        assert_eq!(stats.malformed_packets, 0);
+        // the oversized packet still counts in the sequence
+        assert_eq!(stats.dropped_packets[0], 0);
+    }
+
+    #[test]
+    fn test_send_packet_limits() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        let rc = shub.send_packet(6, &[0]);
+        assert!(matches!(rc, Err(WrapperError::UnknownChannel(6))));
+        let rc = shub.send_packet(2, &[0; 253]);
+        assert!(matches!(rc, Err(WrapperError::PacketTooLarge(257))));
+
+        // sequence numbers wrap around
+        for _ in 0..=256 {
+            assert!(shub.send_packet(2, &[0]).is_ok());
+        }
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets.len(), 257);
+        assert_eq!(fake.sent_packets[256][3], 0);
+    }
+
+    /// Feed pseudo-random packets to the driver: none may cause a panic
+    #[test]
+    fn test_random_packets() {
+        // xorshift32, so that any failure can be reproduced
+        let mut rng_state: u32 = 0x1234_5678;
+        let mut next_rand = move || {
+            rng_state ^= rng_state << 13;
+            rng_state ^= rng_state >> 17;
+            rng_state ^= rng_state << 5;
+            rng_state
+        };
+
+        let mut fake = FakeSensorInterface::new();
+        for _ in 0..5000 {
+            let len = (next_rand() % 64) as usize;
+            let mut packet: Vec<u8> =
+                (0..len).map(|_| next_rand() as u8).collect();
+            // steer most packets to real channels and report IDs
+            if len > 2 {
+                packet[2] %= 8;
+            }
+            if len > 4 && next_rand() % 2 == 0 {
+                packet[4] = [0x00, 0x01, 0xF1, 0xF8, 0xEF, 0xFB, 0x05]
+                    [(next_rand() % 7) as usize];
+            }
+            fake.available_packets.push_back(packet);
+        }
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let mut delay = FakeDelay {};
+        while !shub.sensor_interface.available_packets.is_empty() {
+            let _ = shub.handle_one_message(&mut delay, 1);
+        }
+    }
+
+    #[test]
+    fn test_dropped_packets() {
+        let mut fake = FakeSensorInterface::new();
+        for (pkt_seq, report_seq) in [(0, 0), (1, 1), (4, 5)] {
+            fake.add_available_packet(&[
+                23, 0, 3, pkt_seq, // packet header
+                0xFB, 0, 0, 0, 0, // base timestamp reference
+                0x05, report_seq, 0x03, 0, // rotation vector
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // values
+            ]);
+        }
+        fake.add_available_packet(&[5, 0, 1, 7, 0x01]); // reset complete
+        fake.add_available_packet(&[
+            23, 0, 3, 0, // packet header
+            0xFB, 0, 0, 0, 0, // base timestamp reference
+            0x05, 0, 0x03, 0, // rotation vector
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // values
+        ]);
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let handled = shub.handle_all_messages(&mut FakeDelay {}, 1);
+        assert_eq!(handled.ok(), Some(5));
+        assert_eq!(shub.dropped_packets(3), 2);
+        assert_eq!(shub.dropped_reports(0x05), 3);
+        assert_eq!(shub.dropped_packets(1), 0);
+
+        shub.reset_stats();
+        assert_eq!(shub.dropped_packets(3), 0);
+        assert_eq!(shub.dropped_reports(0x05), 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[5, 0, 1, 0, 0x01]); // reset complete
+        fake.add_available_packet(&[5, 0, 1, 1, 0x77]); // unknown report
+        fake.add_available_packet(&[
+            23, 0, 3, 0, // packet header
+            0xFB, 0, 0, 0, 0, // base timestamp reference
+            0x05, 0, 0x03, 0, // rotation vector
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // values
+        ]);
+        fake.add_available_packet(&[5, 0, 9, 0, 0x01]); // unknown channel
+
+        let mut shub = BNO080::new_with_interface(fake);
+        let mut delay = FakeDelay {};
+        assert!(shub.handle_all_messages(&mut delay, 1).is_err());
+        assert!(shub.flush(0x05, &mut delay).is_err());
+
+        let stats = *shub.stats();
+        assert_eq!(
+            stats,
+            Stats {
+                packets_received: [0, 2, 0, 1, 0, 0],
+                bytes_received: 38,
+                unknown_reports: 1,
+                malformed_packets: 1,
+                timeouts: 1,
+                resets: 1,
+                ..Default::default()
+            }
+        );
+
+        shub.reset_stats();
+        assert_eq!(*shub.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_stats_saturate() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[5, 0, 1, 0, 0x77]); // unknown report
+        let mut shub = BNO080::new_with_interface(fake);
+        shub.stats.unknown_reports = u32::MAX;
+        shub.stats.packets_received[1] = u32::MAX;
+
+        let handled = shub.handle_all_messages(&mut FakeDelay {}, 1);
+        assert_eq!(handled.ok(), Some(1));
+        assert_eq!(shub.stats().unknown_reports, u32::MAX);
+        assert_eq!(shub.stats().packets_received[1], u32::MAX);
+    }
+
+    #[test]
+    fn test_flush_no_response() {
+        let mut shub = BNO080::new_with_interface(FakeSensorInterface::new());
+        let rc = shub.flush(0x05, &mut FakeDelay {});
+        assert!(rc.is_err());
+    }
+
     // #[test]
     // fn test_foo() {
     //     let mut mock_i2c_port = FakeI2cPort::new();
@@ -0,0 +1,744 @@
+use super::{
+    increment, CommandResponse, DeferredRequest, Event, FeatureConfig,
+    InitSubsystem, OscillatorType, PowerState, ProductInfo, SensorCounts,
+    SensorSample, ShtpError, Stats, WrapperError, ADVERTISE_ALL, BNO080,
+    CHANNEL_COMMAND, CHANNEL_EXECUTABLE, CHANNEL_HUB_CONTROL,
+    CMD_GET_ADVERTISEMENT, CMD_SEND_ERROR_LIST, COMMAND_POLL_MS,
+    EXECUTABLE_DEVICE_CMD_ON, EXECUTABLE_DEVICE_CMD_RESET,
+    EXECUTABLE_DEVICE_CMD_SLEEP, PACKET_RECV_BUF_LEN, PACKET_SEND_BUF_LEN,
+    PRODUCT_ID_REQUEST, SH2_CMD_COUNTS, SH2_CMD_GET_OSC_TYPE,
+    SH2_CMD_INITIALIZE, SH2_COUNTS_CLEAR, SH2_COUNTS_GET,
+    SHUB_COMMAND_REQ_LENGTH, SHUB_FORCE_SENSOR_FLUSH,
+};
+use crate::defmt;
+use crate::interface::AsyncSensorInterface;
+use crate::LogFormat;
+use defmt::println;
+use embedded_hal_async::delay::DelayNs;
+
+/// Async version of `BNO080`, for use with an async executor such as
+/// Embassy: whenever the sensor hub has nothing to say, it awaits
+/// rather than blocks, leaving the executor free to run other tasks.
+pub struct BNO080Async<
+    SI,
+    const SEND_BUF_LEN: usize = PACKET_SEND_BUF_LEN,
+    const RECV_BUF_LEN: usize = PACKET_RECV_BUF_LEN,
+> {
+    /// driver state, shared with the blocking driver
+    inner: BNO080<SI, SEND_BUF_LEN, RECV_BUF_LEN>,
+}
+
+impl<SI> BNO080Async<SI> {
+    pub fn new_with_interface(sensor_interface: SI) -> Self {
+        Self::new_with_buffers(sensor_interface)
+    }
+}
+
+impl<SI, const SEND_BUF_LEN: usize, const RECV_BUF_LEN: usize>
+    BNO080Async<SI, SEND_BUF_LEN, RECV_BUF_LEN>
+{
+    /// Use packet buffers of the given sizes,
+    /// as with `BNO080::new_with_buffers`
+    pub fn new_with_buffers(sensor_interface: SI) -> Self {
+        Self {
+            inner: BNO080::new_with_buffers(sensor_interface),
+        }
+    }
+
+    /// Returns previously consumed serial sensor instance.
+    /// Any packets the sensor interface still holds back are not sent:
+    /// call `flush_outbound` first to send them.
+    pub fn free(self) -> SI {
+        self.inner.free()
+    }
+
+    pub fn heading_accuracy(&self) -> f32 {
+        self.inner.heading_accuracy()
+    }
+
+    /// Product ID records most recently reported by the sensor hub
+    pub fn product_info(&self) -> &ProductInfo {
+        self.inner.product_info()
+    }
+
+    /// SHTP errors in the error list most recently sent by the sensor hub
+    pub fn error_list(&self) -> &[ShtpError] {
+        self.inner.error_list()
+    }
+
+    /// Number of packets missing from the sequence received on `channel`
+    pub fn dropped_packets(&self, channel: u8) -> u32 {
+        self.inner.dropped_packets(channel)
+    }
+
+    /// Number of input reports with `report_id` missing from the sequence
+    /// received
+    pub fn dropped_reports(&self, report_id: u8) -> u32 {
+        self.inner.dropped_reports(report_id)
+    }
+
+    /// Configuration of every feature currently enabled
+    pub fn enabled_features(&self) -> impl Iterator<Item = &FeatureConfig> {
+        self.inner.enabled_features()
+    }
+
+    /// Link health counters accumulated since the last `reset_stats`
+    pub fn stats(&self) -> &Stats {
+        self.inner.stats()
+    }
+
+    /// Reset every link health counter to zero
+    pub fn reset_stats(&mut self) {
+        self.inner.reset_stats()
+    }
+
+    /// Take the most recent event noticed while handling messages, if any
+    pub fn take_event(&mut self) -> Option<Event> {
+        self.inner.take_event()
+    }
+
+    /// Choose whether every enabled feature is configured again
+    /// after the sensor hub resets unexpectedly
+    pub fn set_reapply_on_reset(&mut self, reapply: bool) {
+        self.inner.set_reapply_on_reset(reapply)
+    }
+
+    /// Make `init` fail with `WrapperError::InvalidFWVersion` when the
+    /// sensor hub firmware major version is older than `major`.
+    /// By default, any firmware version is accepted.
+    pub fn set_min_fw_version_major(&mut self, major: u8) {
+        self.inner.set_min_fw_version_major(major)
+    }
+
+    /// Power state most recently requested of the sensor hub
+    pub fn power_state(&self) -> PowerState {
+        self.inner.power_state()
+    }
+}
+
+impl<SI, SE, const SEND_BUF_LEN: usize, const RECV_BUF_LEN: usize>
+    BNO080Async<SI, SEND_BUF_LEN, RECV_BUF_LEN>
+where
+    SI: AsyncSensorInterface<SensorError = SE>,
+    SE: core::fmt::Debug + LogFormat,
+{
+    /// The BNO080 starts up with all sensors disabled,
+    /// waiting for the application to configure it.
+    pub async fn init(
+        &mut self,
+        delay_source: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        println!("async init");
+        self.inner.start_init();
+
+        delay_source.delay_ms(1).await;
+        let rc = self.inner.sensor_interface.setup(delay_source).await;
+        self.inner.count_comm_error(rc)?;
+
+        if self.inner.sensor_interface.requires_soft_reset() {
+            delay_source.delay_ms(1).await;
+            self.soft_reset(delay_source).await?;
+            delay_source.delay_ms(150).await;
+            self.eat_all_messages(delay_source).await?;
+            delay_source.delay_ms(50).await;
+            self.eat_all_messages(delay_source).await?;
+        } else {
+            // eat the advertisement response
+            self.eat_one_message(delay_source).await?;
+            // eat the unsolicited initialization response
+            self.eat_one_message(delay_source).await?;
+        }
+
+        self.verify_product_id(delay_source).await?;
+
+        self.inner.finish_init();
+        Ok(())
+    }
+
+    /// Read normalized quaternion, as with `BNO080::rotation_quaternion`
+    pub fn rotation_quaternion(&self) -> Result<[f32; 4], WrapperError<SE>> {
+        Ok(self.inner.rotation_quaternion)
+    }
+
+    /// Read linear acceleration (m/s^2)
+    pub fn linear_accel(&self) -> Result<[f32; 3], WrapperError<SE>> {
+        Ok(self.inner.linear_accel)
+    }
+
+    /// Read gyroscope data (rad/s)
+    pub fn gyro(&self) -> Result<[f32; 3], WrapperError<SE>> {
+        Ok(self.inner.gyro)
+    }
+
+    /// Consume all available messages on the port without processing them
+    pub async fn eat_all_messages(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        while self.eat_one_message(delay).await? > 0 {}
+        Ok(())
+    }
+
+    /// Receive and ignore one message,
+    /// returning the size of the packet received or zero
+    /// if there was no packet to read.
+    pub async fn eat_one_message(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<usize, WrapperError<SE>> {
+        self.receive_packet_with_timeout(delay, 150).await
+    }
+
+    /// Handle any messages with a timeout,
+    /// returning the number of messages handled
+    pub async fn handle_all_messages(
+        &mut self,
+        delay: &mut impl DelayNs,
+        timeout_ms: u8,
+    ) -> Result<u32, WrapperError<SE>> {
+        let mut total_handled: u32 = 0;
+        while self.handle_one_message(delay, timeout_ms).await? > 0 {
+            total_handled += 1;
+        }
+        Ok(total_handled)
+    }
+
+    /// return the number of messages handled
+    pub async fn handle_one_message(
+        &mut self,
+        delay: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<u32, WrapperError<SE>> {
+        let received_len =
+            self.receive_packet_with_timeout(delay, max_ms).await?;
+        if received_len == 0 {
+            self.inner.idle_polled(max_ms);
+            return Ok(0);
+        }
+        self.dispatch_received_packet(received_len, &mut |_| {})
+            .await?;
+        Ok(1)
+    }
+
+    /// Wait for the next packet carrying input reports, however long
+    /// that takes, passing each of its reports to `sink`.
+    /// Any other packet received meanwhile is handled as usual.
+    /// Returns the number of reports passed to `sink`.
+    pub async fn next_reports(
+        &mut self,
+        delay: &mut impl DelayNs,
+        mut sink: impl FnMut(&SensorSample),
+    ) -> Result<u32, WrapperError<SE>> {
+        let mut report_count: u32 = 0;
+        while report_count == 0 {
+            let received_len =
+                self.receive_packet_with_timeout(delay, u8::MAX).await?;
+            if received_len == 0 {
+                continue;
+            }
+            self.dispatch_received_packet(received_len, &mut |sample| {
+                report_count += 1;
+                sink(sample);
+            })
+            .await?;
+        }
+        Ok(report_count)
+    }
+
+    /// Enable a particular report
+    pub async fn enable_report(
+        &mut self,
+        report_id: u8,
+        flags: u8,
+        millis_between_reports: u16,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_feature(&FeatureConfig {
+            report_id,
+            flags,
+            micros_between_reports: (millis_between_reports as u32) * 1000,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Stop the sensor hub from sending a particular report
+    pub async fn disable_report(
+        &mut self,
+        report_id: u8,
+    ) -> Result<(), WrapperError<SE>> {
+        self.enable_feature(&FeatureConfig {
+            report_id,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Configure a feature with full control over its settings,
+    /// as with `BNO080::enable_feature`
+    pub async fn enable_feature(
+        &mut self,
+        config: &FeatureConfig,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body = self.inner.prepare_feature::<SE>(config)?;
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body).await?;
+        self.inner.remember_feature(config);
+        Ok(())
+    }
+
+    /// Send the configuration of every enabled feature to the sensor hub
+    /// again, for instance after it has reset.
+    pub async fn reapply_features(&mut self) -> Result<(), WrapperError<SE>> {
+        let enabled_features = self.inner.enabled_features;
+        for config in enabled_features.iter().flatten() {
+            self.enable_feature(config).await?;
+        }
+        Ok(())
+    }
+
+    /// Ask the sensor hub to immediately deliver any batched reports
+    /// for `report_id`, then handle incoming messages until the hub
+    /// confirms that the flush has completed.
+    pub async fn flush(
+        &mut self,
+        report_id: u8,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body: [u8; 2] = [SHUB_FORCE_SENSOR_FLUSH, report_id];
+
+        self.inner.last_flush_completed = None;
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body).await?;
+        self.handle_messages_until(delay, |shub| {
+            shub.last_flush_completed == Some(report_id)
+        })
+        .await
+    }
+
+    /// Ask the sensor hub to send its full advertisement again,
+    /// and wait for it to arrive
+    pub async fn request_advertisement(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body: [u8; 2] = [CMD_GET_ADVERTISEMENT, ADVERTISE_ALL];
+
+        self.inner.advert_received = false;
+        self.send_packet(CHANNEL_COMMAND, &cmd_body).await?;
+        self.handle_messages_until(delay, |shub| shub.advert_received)
+            .await
+    }
+
+    /// Ask the sensor hub for its list of SHTP errors,
+    /// and wait for it to arrive.
+    /// The errors are then available from `error_list`.
+    pub async fn request_error_list(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body: [u8; 1] = [CMD_SEND_ERROR_LIST];
+
+        self.inner.error_list_received = false;
+        self.inner.error_list_requested = true;
+        self.send_packet(CHANNEL_COMMAND, &cmd_body).await?;
+        self.handle_messages_until(delay, |shub| shub.error_list_received)
+            .await
+    }
+
+    /// Read the sensor hub's statistics for one sensor,
+    /// as with `BNO080::get_counts`
+    pub async fn get_counts(
+        &mut self,
+        report_id: u8,
+        delay: &mut impl DelayNs,
+    ) -> Result<SensorCounts, WrapperError<SE>> {
+        let responses = self
+            .execute_command(
+                SH2_CMD_COUNTS,
+                &[SH2_COUNTS_GET, report_id],
+                2,
+                delay,
+            )
+            .await?;
+        Ok(SensorCounts::from_responses(responses))
+    }
+
+    /// Reset the sensor hub's statistics for one sensor
+    pub async fn clear_counts(
+        &mut self,
+        report_id: u8,
+    ) -> Result<(), WrapperError<SE>> {
+        self.send_command(SH2_CMD_COUNTS, &[SH2_COUNTS_CLEAR, report_id])
+            .await?;
+        // no response will come to carry the request along
+        self.flush_outbound().await
+    }
+
+    /// Ask the sensor hub which clock source it is running from
+    pub async fn oscillator_type(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<OscillatorType, WrapperError<SE>> {
+        let responses = self
+            .execute_command(SH2_CMD_GET_OSC_TYPE, &[], 1, delay)
+            .await?;
+        Ok(OscillatorType::from(responses[0].values[0]))
+    }
+
+    /// Reinitialize part of the sensor hub with the SH-2 initialize
+    /// command, as with `BNO080::reinitialize`
+    pub async fn reinitialize(
+        &mut self,
+        subsystem: InitSubsystem,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        // the hub may announce a reset while reinitializing
+        self.inner.expect_reset();
+        let rc = self
+            .execute_command(SH2_CMD_INITIALIZE, &[subsystem.into()], 1, delay)
+            .await
+            .and_then(BNO080::<SI>::check_initialize_status);
+        self.inner.reset_expected = false;
+        rc
+    }
+
+    /// Put the sensor hub to sleep: every sensor stops except
+    /// those configured to be always on.
+    /// Reports cannot be configured again until `wake` is called.
+    pub async fn sleep(&mut self) -> Result<(), WrapperError<SE>> {
+        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_SLEEP];
+        self.send_packet(CHANNEL_EXECUTABLE, &data).await?;
+        // the application may not read from the sensor hub for a while
+        self.flush_outbound().await?;
+        self.inner.power_state = PowerState::Sleep;
+        Ok(())
+    }
+
+    /// Wake the sensor hub from sleep, resuming every enabled report
+    pub async fn wake(&mut self) -> Result<(), WrapperError<SE>> {
+        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_ON];
+        self.send_packet(CHANNEL_EXECUTABLE, &data).await?;
+        // the sensor hub does not answer, and may have nothing to send
+        self.flush_outbound().await?;
+        self.inner.power_state = PowerState::On;
+        Ok(())
+    }
+
+    /// Send any packets the sensor interface has held back,
+    /// as with `BNO080::flush_outbound`
+    pub async fn flush_outbound(&mut self) -> Result<(), WrapperError<SE>> {
+        let rc = self.inner.sensor_interface.flush_outbound().await;
+        self.inner.count_comm_error(rc)
+    }
+
+    /// Tell the sensor to reset.
+    /// Normally applications should not need to call this directly,
+    /// as it is called during `init`.
+    pub async fn soft_reset(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        self.inner.expect_reset();
+        let data: [u8; 1] = [EXECUTABLE_DEVICE_CMD_RESET];
+        // send command packet and ignore received packets
+        let received_len = self
+            .send_and_receive_packet(CHANNEL_EXECUTABLE, &data, delay)
+            .await?;
+        if received_len > 0 {
+            self.dispatch_received_packet(received_len, &mut |_| {})
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Verify that the sensor returns an expected chip ID
+    /// and runs a supported firmware version
+    async fn verify_product_id(
+        &mut self,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), WrapperError<SE>> {
+        let cmd_body = self.inner.start_product_id_request();
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body).await?;
+
+        // process all incoming messages until we get a product id (or no more data)
+        while !self.inner.prod_id_verified {
+            if self.handle_one_message(delay, 150).await? < 1 {
+                break;
+            }
+        }
+        self.inner.check_product_id()
+    }
+
+    /// Send an SH-2 command request and collect `response_count`
+    /// responses, as with `BNO080::execute_command`
+    async fn execute_command(
+        &mut self,
+        command: u8,
+        params: &[u8],
+        response_count: usize,
+        delay: &mut impl DelayNs,
+    ) -> Result<&[CommandResponse], WrapperError<SE>> {
+        let cmd_body = self.send_command(command, params).await?;
+        let mut wait = self.inner.start_command_wait(&cmd_body, response_count);
+        while !self.inner.command_complete::<SE>(&wait)? {
+            let rc = self.handle_one_message(delay, COMMAND_POLL_MS).await;
+            self.inner.command_polled(&mut wait, rc)?;
+        }
+        Ok(self.inner.command_responses())
+    }
+
+    /// Send an SH-2 command request, returning the request sent
+    async fn send_command(
+        &mut self,
+        command: u8,
+        params: &[u8],
+    ) -> Result<[u8; SHUB_COMMAND_REQ_LENGTH], WrapperError<SE>> {
+        let cmd_body = self.inner.command_request::<SE>(command, params)?;
+        self.send_packet(CHANNEL_HUB_CONTROL, &cmd_body).await?;
+        Ok(cmd_body)
+    }
+
+    /// Handle incoming messages until `done` is satisfied,
+    /// or until no more messages arrive
+    async fn handle_messages_until(
+        &mut self,
+        delay: &mut impl DelayNs,
+        done: impl Fn(&BNO080<SI, SEND_BUF_LEN, RECV_BUF_LEN>) -> bool,
+    ) -> Result<(), WrapperError<SE>> {
+        while !done(&self.inner) {
+            if self.handle_one_message(delay, 150).await? < 1 {
+                increment(&mut self.inner.stats.timeouts);
+                return Err(WrapperError::NoDataAvailable);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a received packet, passing any input reports it contains
+    /// to `sink`, then send any requests it called for
+    async fn dispatch_received_packet(
+        &mut self,
+        received_len: usize,
+        sink: &mut impl FnMut(&SensorSample),
+    ) -> Result<(), WrapperError<SE>> {
+        let rc = self.inner.process_received_packet(received_len, sink);
+        self.send_deferred_requests().await?;
+        rc
+    }
+
+    /// Send the requests decided on while handling a received packet
+    async fn send_deferred_requests(&mut self) -> Result<(), WrapperError<SE>> {
+        while let Some(request) = self.inner.take_deferred_request() {
+            match request {
+                DeferredRequest::ProductId => {
+                    self.send_packet(CHANNEL_HUB_CONTROL, &PRODUCT_ID_REQUEST)
+                        .await?;
+                }
+                DeferredRequest::ReapplyFeatures => {
+                    self.reapply_features().await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send packet from our packet send buf
+    async fn send_packet(
+        &mut self,
+        channel: u8,
+        body_data: &[u8],
+    ) -> Result<usize, WrapperError<SE>> {
+        let inner = &mut self.inner;
+        let packet_length = inner.prep_send_packet::<SE>(channel, body_data)?;
+        let rc = inner
+            .sensor_interface
+            .write_packet(&inner.packet_send_buf[..packet_length])
+            .await;
+        inner.count_comm_error(rc)?;
+        Ok(packet_length)
+    }
+
+    /// Send a packet and receive the response
+    async fn send_and_receive_packet(
+        &mut self,
+        channel: u8,
+        body_data: &[u8],
+        delay: &mut impl DelayNs,
+    ) -> Result<usize, WrapperError<SE>> {
+        let inner = &mut self.inner;
+        let send_packet_length =
+            inner.prep_send_packet::<SE>(channel, body_data)?;
+        let rc = inner
+            .sensor_interface
+            .send_and_receive_packet(
+                &inner.packet_send_buf[..send_packet_length],
+                &mut inner.packet_recv_buf,
+                delay,
+            )
+            .await;
+        let recv_packet_length = inner.count_comm_error(rc)?;
+        inner.count_packet_received(recv_packet_length);
+        Ok(recv_packet_length)
+    }
+
+    /// Read one packet into the receive buffer
+    async fn receive_packet_with_timeout(
+        &mut self,
+        delay: &mut impl DelayNs,
+        max_ms: u8,
+    ) -> Result<usize, WrapperError<SE>> {
+        let inner = &mut self.inner;
+        inner.packet_recv_buf[0] = 0;
+        inner.packet_recv_buf[1] = 0;
+        let rc = inner
+            .sensor_interface
+            .read_with_timeout(&mut inner.packet_recv_buf, delay, max_ms)
+            .await;
+        let packet_len = inner.count_comm_error(rc)?;
+        inner.count_packet_received(packet_len);
+        inner.last_packet_len_received = packet_len;
+        Ok(packet_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BNO080Async;
+    use crate::interface::mock_interface::{FakeDelay, FakeSensorInterface};
+    use crate::wrapper::{
+        Event, OscillatorType, PowerState, ResetCause, SensorSample,
+        WrapperError,
+    };
+    use core::future::Future;
+    use core::task::{Context, Poll, Waker};
+
+    /// Run a future that never has to wait for anything to completion
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = core::pin::pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_product_id() {
+        let mut fake = FakeSensorInterface::new();
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF8, 0x01, 3, 2, // power on reset, version 3.2
+            0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, // part, build
+        ]);
+
+        let mut shub = BNO080Async::new_with_interface(fake);
+        let rc = block_on(shub.verify_product_id(&mut FakeDelay {}));
+        assert!(rc.is_ok());
+        assert_eq!(shub.product_info().reset_cause(), ResetCause::PowerOn);
+
+        // nothing left to answer a second request
+        let rc = block_on(shub.verify_product_id(&mut FakeDelay {}));
+        assert!(matches!(rc, Err(WrapperError::InvalidChipId(0))));
+    }
+
+    #[test]
+    fn test_next_reports() {
+        let mut fake = FakeSensorInterface::new();
+        // an unrelated packet, then a rotation vector report
+        fake.add_available_packet(&[6, 0, 2, 0, 0xEF, 0x05]);
+        fake.add_available_packet(&[
+            23, 0, 3, 0, // packet header
+            0xFB, 0, 0, 0, 0, // base timestamp
+            0x05, 1, 0x03, 0, // rotation vector, no delay
+            0x00, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, // values
+        ]);
+
+        let mut shub = BNO080Async::new_with_interface(fake);
+        let mut samples: [Option<SensorSample>; 2] = [None; 2];
+        let mut sample_count = 0;
+        let rc = block_on(shub.next_reports(&mut FakeDelay {}, |sample| {
+            samples[sample_count] = Some(*sample);
+            sample_count += 1;
+        }));
+        assert_eq!(rc.ok(), Some(1));
+        assert_eq!(samples[0].map(|s| s.report_id), Some(0x05));
+        assert_eq!(shub.inner.last_flush_completed, Some(0x05));
+        assert_eq!(shub.rotation_quaternion().ok(), Some([1.0, 0.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn test_unexpected_reset() {
+        let mut shub =
+            BNO080Async::new_with_interface(FakeSensorInterface::new());
+        shub.inner.initialized = true;
+        shub.set_reapply_on_reset(true);
+        assert!(block_on(shub.enable_report(0x05, 0, 10)).is_ok());
+
+        // reset complete, unsolicited initialize response, then product ID
+        let fake = &mut shub.inner.sensor_interface;
+        fake.add_available_packet(&[5, 0, 1, 0, 1]);
+        fake.add_available_packet(&[
+            20, 0, 2, 0, 0xF1, 0, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        fake.add_available_packet(&[
+            20, 0, 2, 0, // packet header
+            0xF8, 0x03, 3, 2, // watchdog reset, version 3.2
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // part, build
+        ]);
+        let handled = block_on(shub.handle_all_messages(&mut FakeDelay {}, 1));
+        assert_eq!(handled.ok(), Some(3));
+        assert_eq!(shub.take_event(), Some(Event::Reset(ResetCause::Watchdog)));
+
+        let fake = shub.free();
+        // enable, product ID request, then enable again
+        assert_eq!(fake.sent_packets.len(), 3);
+        assert_eq!(fake.sent_packets[1][4], 0xF9);
+        assert_eq!(fake.sent_packets[2][4..6], [0xFD, 0x05]);
+    }
+
+    #[test]
+    fn test_sleep_and_wake() {
+        let mut shub =
+            BNO080Async::new_with_interface(FakeSensorInterface::new());
+        assert!(block_on(shub.sleep()).is_ok());
+        assert_eq!(shub.power_state(), PowerState::Sleep);
+        assert!(matches!(
+            block_on(shub.enable_report(0x05, 0, 10)),
+            Err(WrapperError::SensorAsleep)
+        ));
+
+        assert!(block_on(shub.wake()).is_ok());
+        assert_eq!(shub.power_state(), PowerState::On);
+        assert!(block_on(shub.enable_report(0x05, 0, 10)).is_ok());
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets.len(), 3);
+        assert_eq!(fake.sent_packets[0], [5, 0, 1, 0, 3]);
+        assert_eq!(fake.sent_packets[1], [5, 0, 1, 1, 2]);
+        // neither command is answered, so each is flushed out
+        assert_eq!(fake.flushes, 2);
+    }
+
+    #[test]
+    fn test_oscillator_type() {
+        let mut fake = FakeSensorInterface::new();
+        // an unrelated packet arrives ahead of the command response
+        fake.add_available_packet(&[6, 0, 2, 0, 0xEF, 0x05]);
+        fake.add_available_packet(&[
+            20, 0, 2, 1, // packet header
+            0xF1, 0, 0x0A, 0, 0, // oscillator type response
+            0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        let mut shub = BNO080Async::new_with_interface(fake);
+        let osc_type = block_on(shub.oscillator_type(&mut FakeDelay {}));
+        assert_eq!(osc_type.ok(), Some(OscillatorType::ExternalCrystal));
+
+        // nothing left to answer a second request
+        let rc = block_on(shub.oscillator_type(&mut FakeDelay {}));
+        assert!(matches!(rc, Err(WrapperError::CommandTimeout(0x0A))));
+
+        let fake = shub.free();
+        assert_eq!(fake.sent_packets[0][4..7], [0xF2, 0, 0x0A]);
+        assert_eq!(fake.sent_packets[1][4..7], [0xF2, 1, 0x0A]);
+    }
+}